@@ -0,0 +1,55 @@
+#![allow(clippy::needless_return)]
+
+use clap::Parser;
+use std::io::Write;
+use std::sync::Arc;
+use tagged::config;
+use tagged::storage::*;
+
+/// Writes documents matching the tags and the time range to stdout, oldest first.
+/// The server must be stopped, the data dir is locked by it; use its /export otherwise
+#[derive(Debug, Parser)]
+#[command(name = "tagged-export")]
+struct ExportArgs {
+	#[command(flatten)]
+	config: config::Args,
+	/// Documents must have all of the tags
+	#[arg(long = "tag")]
+	tags: Vec<String>,
+	/// Start of the time range in milliseconds, inclusive
+	#[arg(long, default_value_t = MIN_TIME)]
+	from: Timestamp,
+	/// End of the time range in milliseconds, inclusive
+	#[arg(long, default_value_t = MAX_TIME)]
+	to: Timestamp,
+	#[arg(long, value_enum, default_value_t = ExportFormat::Jsonl)]
+	format: ExportFormat,
+}
+
+#[tokio::main]
+async fn main() -> Result<(), anyhow::Error> {
+	env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("warn")).init();
+	let args = ExportArgs::parse();
+	let config = args.config.load_config()?;
+	if args.from > args.to {
+		return Err(anyhow::anyhow!("from must be less than to"));
+	}
+	let query = Query::new(args.tags.clone(), (args.from, args.to));
+
+	let (storage, stop) = Storage::new(config)?;
+	let storage_copy = Arc::clone(&storage);
+	let format = args.format;
+	let result = tokio::task::spawn_blocking(move || {
+		let stdout = std::io::stdout();
+		let mut out = std::io::BufWriter::new(stdout.lock());
+		let count =
+			storage_copy.export(&query, |record| write_record(&mut out, &record, format))?;
+		out.flush()?;
+		return Ok::<_, anyhow::Error>(count);
+	})
+	.await?;
+	stop.await?;
+	let count = result?;
+	log::info!("exported {} documents", count);
+	return Ok(());
+}
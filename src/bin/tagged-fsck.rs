@@ -0,0 +1,66 @@
+#![allow(clippy::needless_return)]
+
+use clap::Parser;
+use std::path::PathBuf;
+use tagged::storage::*;
+
+/// Checks the block files in the data dir, exits with 1 if there are problems
+#[derive(Debug, Parser)]
+#[command(name = "tagged-fsck")]
+struct Args {
+	/// Data dir of the storage, it must not be opened by the server
+	data_dir: PathBuf,
+	/// Move corrupt files to the quarantine dir and drop them and the missing files
+	/// from the manifest
+	#[arg(long)]
+	quarantine: bool,
+}
+
+fn main() -> Result<(), anyhow::Error> {
+	env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("warn")).init();
+	let args = Args::parse();
+
+	// the server can't change the files between the check and the quarantine
+	let _lock = if args.quarantine {
+		Some(lock_dir(&args.data_dir)?)
+	} else {
+		None
+	};
+	let report = check_dir(&args.data_dir)?;
+	for file in report.files.iter() {
+		if file.problems.is_empty() {
+			println!("ok      {}", file.name);
+			continue;
+		}
+		println!("corrupt {}", file.name);
+		for problem in file.problems.iter() {
+			println!("          {}", problem);
+		}
+	}
+	for name in report.missing.iter() {
+		println!("missing {}", name);
+	}
+	for name in report.orphans.iter() {
+		println!("orphan  {}", name);
+	}
+	println!(
+		"checked {} files: {} corrupt, {} missing, {} orphans",
+		report.files.len(),
+		report.corrupt().count(),
+		report.missing.len(),
+		report.orphans.len()
+	);
+
+	if args.quarantine {
+		let moved = quarantine(&args.data_dir, &report)?;
+		println!(
+			"quarantined {} files, dropped {} missing files from the manifest",
+			moved.len(),
+			report.missing.len()
+		);
+	}
+	if !report.is_ok() {
+		std::process::exit(1);
+	}
+	return Ok(());
+}
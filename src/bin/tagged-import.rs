@@ -0,0 +1,79 @@
+#![allow(clippy::needless_return)]
+
+use clap::Parser;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tagged::config;
+use tagged::storage::*;
+
+/// Loads documents into block files of the storage. All imported documents must be
+/// older than the oldest document in the storage, otherwise nothing is imported.
+/// The server must be stopped, the data dir is locked by it
+#[derive(Debug, Parser)]
+#[command(name = "tagged-import")]
+struct ImportArgs {
+	#[command(flatten)]
+	config: config::Args,
+	/// JSONL or CSV files with key, tags and timestamp of every document
+	#[arg(required = true)]
+	files: Vec<PathBuf>,
+	/// Format of the files, chosen by the extension by default
+	#[arg(long, value_enum)]
+	format: Option<Format>,
+}
+
+#[tokio::main]
+async fn main() -> Result<(), anyhow::Error> {
+	env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+	let args = ImportArgs::parse();
+	let config = args.config.load_config()?;
+
+	std::fs::create_dir_all(config.data_dir())?;
+	let (storage, stop) = Storage::new(config)?;
+	let storage_copy = Arc::clone(&storage);
+	let result = tokio::task::spawn_blocking(move || {
+		// all files are sorted together, so they can come in any order
+		let mut documents = 0;
+		let records = args
+			.files
+			.iter()
+			.flat_map(|path| read_file(path, args.format))
+			.inspect(|record| {
+				if record.is_ok() {
+					documents += 1;
+				}
+			});
+		let files = storage_copy.import(records)?;
+		return Ok::<_, anyhow::Error>((documents, files));
+	})
+	.await?;
+	stop.await?;
+	let (documents, files) = result?;
+	println!("imported {} documents into {} files", documents, files);
+	return Ok(());
+}
+
+// documents of the file, the errors name the file
+fn read_file<'a>(
+	path: &'a Path,
+	format: Option<Format>,
+) -> Box<dyn Iterator<Item = Result<Record, anyhow::Error>> + 'a> {
+	let format = match format.or_else(|| Format::from_path(path)) {
+		Some(format) => format,
+		None => {
+			let err = anyhow::anyhow!("unknown format of {}", path.display());
+			return Box::new(std::iter::once(Err(err)));
+		}
+	};
+	let file = match std::fs::File::open(path) {
+		Ok(file) => file,
+		Err(err) => {
+			let err = anyhow::anyhow!("can't open {}: {}", path.display(), err);
+			return Box::new(std::iter::once(Err(err)));
+		}
+	};
+	println!("reading {}", path.display());
+	return Box::new(read_import(file, format).map(move |record| {
+		record.map_err(|err| anyhow::anyhow!("can't read {}: {}", path.display(), err))
+	}));
+}
@@ -0,0 +1,128 @@
+#![allow(clippy::needless_return)]
+
+use clap::Parser;
+use std::io::Write;
+use std::path::PathBuf;
+use tagged::storage::*;
+
+/// Prints the layout and the contents of a block file
+#[derive(Debug, Parser)]
+#[command(name = "tagged-inspect")]
+struct Args {
+	/// Block file to inspect
+	file: PathBuf,
+	/// Tags to print, with their posting lengths
+	#[arg(long, default_value_t = 100)]
+	tags: usize,
+	/// Keys to print from the start and from the end of the block
+	#[arg(long, default_value_t = 5)]
+	keys: usize,
+	/// Print every document as a JSON line instead
+	#[arg(long)]
+	dump: bool,
+}
+
+fn section(name: &str, start: Offset, end: Offset) {
+	println!(
+		"  {:<10} offset={:<12} size={}",
+		name,
+		start,
+		format_bytes(end.saturating_sub(start))
+	);
+}
+
+// problems of the block, the file is fully loaded to find them
+fn check(args: &Args, block: &mut BlockFile<std::fs::File>) -> Result<Vec<String>, anyhow::Error> {
+	let file_len = std::fs::metadata(&args.file)?.len();
+	return Ok(block.verify(file_len));
+}
+
+fn print_problems(problems: &[String]) {
+	eprintln!("problems:");
+	for problem in problems.iter() {
+		eprintln!("  {}", problem);
+	}
+}
+
+fn inspect(args: &Args, block: &mut BlockFile<std::fs::File>) -> Result<(), anyhow::Error> {
+	let header = block.header().clone();
+	let end = header.start + header.size;
+	println!("file: {}", args.file.display());
+	println!("documents: {}", header.count);
+	println!("range: {}..={}", header.from, header.to);
+	println!("size: {}", format_bytes(header.size));
+	println!("sections:");
+	section("header", header.start, header.tags);
+	section("tags", header.tags, header.keys);
+	section("keys", header.keys, header.timestamps);
+	section(
+		"timestamps",
+		header.timestamps,
+		header.index.first().cloned().unwrap_or(end),
+	);
+	let payloads = Some(header.payloads).filter(|offset| *offset != 0);
+	section(
+		"indexes",
+		header.index.first().cloned().unwrap_or(end),
+		payloads.unwrap_or(end),
+	);
+	if let Some(payloads) = payloads {
+		section("payloads", payloads, end);
+	}
+
+	// the contents of the broken block can't be read, only its layout is printed
+	let problems = check(args, block)?;
+	if !problems.is_empty() {
+		print_problems(&problems);
+		std::process::exit(1);
+	}
+
+	block.read_column(Column::Tags)?;
+	let tags = block.get_tags().to_vec();
+	println!("tags: {}", tags.len());
+	for (id, tag) in tags.iter().enumerate().take(args.tags) {
+		println!("  {} postings={}", tag, block.get_index_len(id));
+	}
+	if tags.len() > args.tags {
+		println!("  ... {} more", tags.len() - args.tags);
+	}
+
+	block.read_column(Column::Keys)?;
+	block.read_column(Column::Timestamps)?;
+	let count = header.count as usize;
+	let head = args.keys.min(count);
+	let tail = std::cmp::max(head, count.saturating_sub(args.keys));
+	println!("keys:");
+	for id in (0..head).chain(tail..count) {
+		if id == tail && tail > head {
+			println!("  ... {} more", tail - head);
+		}
+		println!("  {} {}", block.get_timestamp(id), block.get_key(id));
+	}
+	return Ok(());
+}
+
+fn dump(args: &Args, block: &mut BlockFile<std::fs::File>) -> Result<(), anyhow::Error> {
+	let problems = check(args, block)?;
+	if !problems.is_empty() {
+		print_problems(&problems);
+		std::process::exit(1);
+	}
+	let stdout = std::io::stdout();
+	let mut out = std::io::BufWriter::new(stdout.lock());
+	for record in read_records(block)? {
+		serde_json::to_writer(&mut out, &record)?;
+		out.write_all(b"\n")?;
+	}
+	out.flush()?;
+	return Ok(());
+}
+
+fn main() -> Result<(), anyhow::Error> {
+	let args = Args::parse();
+	let mut block = open_file(&args.file)?;
+	if args.dump {
+		return dump(&args, &mut block);
+	}
+	return inspect(&args, &mut block);
+}
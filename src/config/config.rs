@@ -0,0 +1,103 @@
+use crate::storage::{parse_bytes, parse_duration, Config};
+use clap::Parser;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Every option can be set in the config file, then overridden by the environment
+/// variable, then by the command line flag.
+#[derive(Debug, Parser)]
+#[command(name = "tagged", about = "Storage of documents searchable by tags")]
+pub struct Args {
+	/// Config file in TOML or JSON format, chosen by the extension
+	#[arg(long, env = "TAGGED_CONFIG")]
+	pub config: Option<PathBuf>,
+	/// Address of the HTTP server
+	#[arg(long, env = "TAGGED_LISTEN", default_value = "127.0.0.1:3000")]
+	pub listen: SocketAddr,
+	/// Directory with the block files
+	#[arg(long, env = "TAGGED_DATA_DIR")]
+	pub data_dir: Option<PathBuf>,
+	/// Size of the active block, when it's sealed, like 4MiB.
+	/// Size units are binary: k, kb and KiB all mean 1024 bytes
+	#[arg(long, env = "TAGGED_MAX_ACTIVE_SIZE", value_parser = parse_bytes)]
+	pub max_active_size: Option<u64>,
+	/// Size of the in memory block, when it's written on disk, like 256MiB.
+	/// Size units are binary: m, mb and MiB all mean 1024 KiB
+	#[arg(long, env = "TAGGED_MAX_BLOCK_SIZE", value_parser = parse_bytes)]
+	pub max_block_size: Option<u64>,
+	/// Age of the oldest active document, when the active block is sealed, like 60s
+	#[arg(long, env = "TAGGED_MAX_ACTIVE_AGE", value_parser = parse_duration)]
+	pub max_active_age: Option<Duration>,
+	/// Age of the oldest in memory document, when the block is written on disk, like 10m
+	#[arg(long, env = "TAGGED_MAX_BLOCK_AGE", value_parser = parse_duration)]
+	pub max_block_age: Option<Duration>,
+	/// File blocks with loaded columns or indexes
+	#[arg(long, env = "TAGGED_MAX_LOADED_BLOCKS")]
+	pub max_loaded_blocks: Option<usize>,
+	/// Sealed blocks waiting for the save worker, before writers have to wait
+	#[arg(long, env = "TAGGED_MAX_PENDING_BLOCKS")]
+	pub max_pending_blocks: Option<usize>,
+	/// Reject pushes instead of waiting for the save worker
+	#[arg(long, env = "TAGGED_REJECT_ON_BACKPRESSURE")]
+	pub reject_on_backpressure: Option<bool>,
+	/// Blocks written on disk at the same time
+	#[arg(long, env = "TAGGED_SAVE_WORKERS")]
+	pub save_workers: Option<usize>,
+	/// Active blocks filled in parallel
+	#[arg(long, env = "TAGGED_ACTIVE_SHARDS")]
+	pub active_shards: Option<usize>,
+}
+
+impl Args {
+	/// Effective config: defaults, the config file, then the overrides, validated
+	pub fn load_config(&self) -> Result<Config, anyhow::Error> {
+		let mut config = match &self.config {
+			Some(path) => read_config(path)?,
+			None => Config::default(),
+		};
+
+		macro_rules! set {
+			($($field:ident),*) => {
+				$(if let Some(value) = &self.$field {
+					config.$field = value.clone();
+				})*
+			};
+		}
+		set!(
+			data_dir,
+			max_active_size,
+			max_block_size,
+			max_active_age,
+			max_block_age,
+			max_loaded_blocks,
+			max_pending_blocks,
+			reject_on_backpressure,
+			save_workers,
+			active_shards
+		);
+
+		config.validate()?;
+		return Ok(config);
+	}
+}
+
+pub fn read_config(path: &Path) -> Result<Config, anyhow::Error> {
+	let data = std::fs::read_to_string(path)
+		.map_err(|err| anyhow::anyhow!("can't read {}: {}", path.display(), err))?;
+	let config = match path.extension().and_then(|ext| ext.to_str()) {
+		Some("toml") => toml::from_str(&data)?,
+		Some("json") => serde_json::from_str(&data)?,
+		_ => {
+			return Err(anyhow::anyhow!(
+				"unknown config format {}, expected toml or json",
+				path.display()
+			))
+		}
+	};
+	return Ok(config);
+}
+
+#[cfg(test)]
+#[path = "tests/config.rs"]
+mod config_test;
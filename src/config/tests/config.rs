@@ -0,0 +1,125 @@
+use super::*;
+use crate::tests;
+use std::sync::{Mutex, MutexGuard};
+
+// the environment is global for the process, so the tests, that parse the args,
+// don't run in parallel with the one, that changes it
+static ENV: Mutex<()> = Mutex::new(());
+
+fn lock_env() -> MutexGuard<'static, ()> {
+	return ENV.lock().unwrap_or_else(|err| err.into_inner());
+}
+
+#[test]
+fn defaults() -> Result<(), anyhow::Error> {
+	tests::run_with_logger(|| {
+		let _env = lock_env();
+		let args = Args::try_parse_from(["tagged"])?;
+		assert_eq!(args.load_config()?, Config::default());
+		assert_eq!(args.listen, "127.0.0.1:3000".parse()?);
+
+		Ok(())
+	})
+}
+
+#[test]
+fn file() -> Result<(), anyhow::Error> {
+	tests::run_basic(tests::test_name!(), |dir| {
+		let toml = dir.join("config.toml");
+		std::fs::write(&toml, "data_dir = \"/tmp/toml\"\nmax_active_size = 100\n")?;
+		let config = read_config(&toml)?;
+		assert_eq!(config.data_dir, PathBuf::from("/tmp/toml"));
+		assert_eq!(config.max_active_size, 100);
+		assert_eq!(config.max_block_size, Config::default().max_block_size);
+
+		let json = dir.join("config.json");
+		std::fs::write(&json, r#"{"max_block_size": 1000, "save_workers": 3}"#)?;
+		let config = read_config(&json)?;
+		assert_eq!(config.max_block_size, 1000);
+		assert_eq!(config.save_workers, 3);
+
+		// sizes can have units
+		let units = dir.join("units.toml");
+		std::fs::write(
+			&units,
+			"max_active_size = \"64KiB\"\nmax_block_size = \"1 MiB\"\nmax_block_age = \"5m\"\n",
+		)?;
+		let config = read_config(&units)?;
+		assert_eq!(config.max_block_age, Duration::from_secs(5 * 60));
+		assert_eq!(config.max_active_size, 64 * 1024);
+		assert_eq!(config.max_block_size, 1024 * 1024);
+		assert_eq!(
+			toml::from_str::<Config>(&toml::to_string(&config)?)?,
+			config
+		);
+
+		let unknown = dir.join("config.json");
+		std::fs::write(&unknown, r#"{"max_blocks_size": 1000}"#)?;
+		assert!(read_config(&unknown).is_err());
+		assert!(read_config(&dir.join("config.yaml")).is_err());
+
+		Ok(())
+	})
+}
+
+#[test]
+fn overrides() -> Result<(), anyhow::Error> {
+	tests::run_basic(tests::test_name!(), |dir| {
+		let _env = lock_env();
+		let path = dir.join("config.toml");
+		std::fs::write(&path, "max_active_size = 100\nmax_block_size = 1000\n")?;
+
+		let path = path.to_str().unwrap();
+		let args = Args::try_parse_from([
+			"tagged",
+			"--config",
+			path,
+			"--max-block-size",
+			"2KiB",
+			"--max-active-age",
+			"30s",
+			"--reject-on-backpressure",
+			"true",
+			"--listen",
+			"0.0.0.0:8080",
+		])?;
+		let config = args.load_config()?;
+		assert_eq!(config.max_active_size, 100);
+		assert_eq!(config.max_block_size, 2048);
+		assert_eq!(config.max_active_age, Duration::from_secs(30));
+		assert!(config.reject_on_backpressure);
+		assert_eq!(args.listen, "0.0.0.0:8080".parse()?);
+
+		// flags take precedence over the environment
+		std::env::set_var("TAGGED_ACTIVE_SHARDS", "3");
+		let env = Args::try_parse_from(["tagged", "--config", path]);
+		let flag = Args::try_parse_from(["tagged", "--config", path, "--active-shards", "5"]);
+		std::env::remove_var("TAGGED_ACTIVE_SHARDS");
+		assert_eq!(env?.load_config()?.active_shards, 3);
+		assert_eq!(flag?.load_config()?.active_shards, 5);
+
+		Ok(())
+	})
+}
+
+#[test]
+fn validate() -> Result<(), anyhow::Error> {
+	tests::run_with_logger(|| {
+		let _env = lock_env();
+		let args = Args::try_parse_from([
+			"tagged",
+			"--max-active-size",
+			"100",
+			"--max-block-size",
+			"100",
+		])?;
+		assert!(args.load_config().is_err());
+
+		assert!(Args::try_parse_from(["tagged", "--max-block-size", "2 parsecs"]).is_err());
+
+		let args = Args::try_parse_from(["tagged", "--save-workers", "0"])?;
+		assert!(args.load_config().is_err());
+
+		Ok(())
+	})
+}
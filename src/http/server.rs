@@ -0,0 +1,253 @@
+use crate::storage::*;
+use futures::Future;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+const DEFAULT_TAGS_LIMIT: usize = 100;
+const MAX_TAGS_LIMIT: usize = 1000;
+const DEFAULT_QUERY_LIMIT: usize = 100;
+const MAX_QUERY_LIMIT: usize = 10000;
+// exported documents are sent in chunks of about this size
+const EXPORT_CHUNK_SIZE: usize = 64 * 1024;
+
+type HandlerResult = Result<Response<Body>, HttpError>;
+
+#[derive(Debug)]
+pub struct HttpError {
+	status: StatusCode,
+	message: String,
+}
+
+impl HttpError {
+	pub fn new(status: StatusCode, message: impl ToString) -> HttpError {
+		HttpError {
+			status,
+			message: message.to_string(),
+		}
+	}
+
+	fn into_response(self) -> Response<Body> {
+		let mut builder = Response::builder().status(self.status);
+		if self.status == StatusCode::TOO_MANY_REQUESTS {
+			builder = builder.header(hyper::header::RETRY_AFTER, "1");
+		}
+		builder.body(Body::from(self.message)).unwrap()
+	}
+}
+
+#[derive(Deserialize, Debug, Default)]
+struct TagsQuery {
+	#[serde(default)]
+	prefix: String,
+	after: Option<String>,
+	from: Option<Timestamp>,
+	to: Option<Timestamp>,
+	limit: Option<usize>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+struct SearchQuery {
+	// comma separated
+	#[serde(default)]
+	tags: String,
+	from: Option<Timestamp>,
+	to: Option<Timestamp>,
+	limit: Option<usize>,
+	// return payloads of the matches
+	#[serde(default)]
+	payload: bool,
+}
+
+#[derive(Deserialize, Debug, Default)]
+struct ExportQuery {
+	// comma separated
+	#[serde(default)]
+	tags: String,
+	from: Option<Timestamp>,
+	to: Option<Timestamp>,
+	#[serde(default)]
+	format: ExportFormat,
+}
+
+pub async fn serve(
+	storage: Arc<Storage>,
+	addr: SocketAddr,
+	shutdown: impl Future<Output = ()>,
+) -> Result<(), anyhow::Error> {
+	let make_service = make_service_fn(move |_| {
+		let storage = Arc::clone(&storage);
+		async move { Ok::<_, Infallible>(service_fn(move |req| handle(Arc::clone(&storage), req))) }
+	});
+
+	let server = Server::try_bind(&addr)?.serve(make_service);
+	log::info!("listening on {}", addr);
+	server.with_graceful_shutdown(shutdown).await?;
+	return Ok(());
+}
+
+pub async fn handle(
+	storage: Arc<Storage>,
+	req: Request<Body>,
+) -> Result<Response<Body>, Infallible> {
+	let result = match (req.method(), req.uri().path()) {
+		(&Method::GET, "/tags") => list_tags(storage, req).await,
+		(&Method::GET, "/query") => query(storage, req).await,
+		(&Method::GET, "/export") => export(storage, req),
+		(&Method::POST, "/push") => push(storage, req).await,
+		(&Method::GET, "/stats") => stats(storage).await,
+		(&Method::GET, "/metrics") => metrics(storage).await,
+		_ => Err(HttpError::new(StatusCode::NOT_FOUND, "not found")),
+	};
+	return Ok(result.unwrap_or_else(HttpError::into_response));
+}
+
+async fn list_tags(storage: Arc<Storage>, req: Request<Body>) -> HandlerResult {
+	let query: TagsQuery = parse_query(&req)?;
+	let range = parse_range(query.from, query.to)?;
+	let limit = query
+		.limit
+		.unwrap_or(DEFAULT_TAGS_LIMIT)
+		.min(MAX_TAGS_LIMIT);
+
+	let tags = tokio::task::spawn_blocking(move || {
+		storage.list_tags(&query.prefix, query.after.as_deref(), range, limit)
+	})
+	.await
+	.map_err(|err| HttpError::new(StatusCode::INTERNAL_SERVER_ERROR, err))?
+	.map_err(|err| HttpError::new(StatusCode::INTERNAL_SERVER_ERROR, err))?;
+	return json(&tags);
+}
+
+async fn query(storage: Arc<Storage>, req: Request<Body>) -> HandlerResult {
+	let params: SearchQuery = parse_query(&req)?;
+	let range = parse_range(params.from, params.to)?;
+	let limit = params
+		.limit
+		.unwrap_or(DEFAULT_QUERY_LIMIT)
+		.min(MAX_QUERY_LIMIT);
+	let query = Query::new(parse_tags(&params.tags), range).with_payload(params.payload);
+
+	let matches = tokio::task::spawn_blocking(move || storage.query(&query, limit))
+		.await
+		.map_err(|err| HttpError::new(StatusCode::INTERNAL_SERVER_ERROR, err))?
+		.map_err(|err| HttpError::new(StatusCode::INTERNAL_SERVER_ERROR, err))?;
+	return json(&matches);
+}
+
+// documents are streamed, while they're read, so the error in the middle
+// can only abort the response
+fn export(storage: Arc<Storage>, req: Request<Body>) -> HandlerResult {
+	let params: ExportQuery = parse_query(&req)?;
+	let range = parse_range(params.from, params.to)?;
+	let query = Query::new(parse_tags(&params.tags), range);
+	let format = params.format;
+
+	let (sender, receiver) = tokio::sync::mpsc::channel::<Result<Vec<u8>, anyhow::Error>>(4);
+	tokio::task::spawn_blocking(move || {
+		let mut chunk = Vec::default();
+		let result = storage.export(&query, |record| {
+			write_record(&mut chunk, &record, format)?;
+			if chunk.len() >= EXPORT_CHUNK_SIZE {
+				sender
+					.blocking_send(Ok(std::mem::take(&mut chunk)))
+					.map_err(|_| anyhow::anyhow!("export is cancelled"))?;
+			}
+			return Ok(());
+		});
+		let last = result.map(|_| chunk);
+		if let Err(err) = &last {
+			log::warn!("export failed: {}", err);
+		}
+		sender.blocking_send(last).ok();
+	});
+	let stream = futures::stream::unfold(receiver, |mut receiver| async move {
+		let chunk = receiver.recv().await?;
+		return Some((chunk, receiver));
+	});
+	return Ok(Response::builder()
+		.header(hyper::header::CONTENT_TYPE, format.content_type())
+		.body(Body::wrap_stream(stream))
+		.unwrap());
+}
+
+async fn push(storage: Arc<Storage>, req: Request<Body>) -> HandlerResult {
+	let body = hyper::body::to_bytes(req.into_body())
+		.await
+		.map_err(|err| HttpError::new(StatusCode::BAD_REQUEST, err))?;
+	let docs: Vec<Document> = serde_json::from_slice(&body)
+		.map_err(|err| HttpError::new(StatusCode::BAD_REQUEST, err))?;
+
+	storage.push_batch(docs).await.map_err(|err| {
+		if err.is::<Overloaded>() {
+			HttpError::new(StatusCode::TOO_MANY_REQUESTS, err)
+		} else {
+			HttpError::new(StatusCode::INTERNAL_SERVER_ERROR, err)
+		}
+	})?;
+	return Ok(Response::builder()
+		.status(StatusCode::NO_CONTENT)
+		.body(Body::empty())
+		.unwrap());
+}
+
+async fn stats(storage: Arc<Storage>) -> HandlerResult {
+	let stats = tokio::task::spawn_blocking(move || storage.stats())
+		.await
+		.map_err(|err| HttpError::new(StatusCode::INTERNAL_SERVER_ERROR, err))?;
+	return json(&stats);
+}
+
+// block locks can be held by the writers and the compaction, so it's not read on the executor
+async fn metrics(storage: Arc<Storage>) -> HandlerResult {
+	let metrics = tokio::task::spawn_blocking(move || storage.metrics())
+		.await
+		.map_err(|err| HttpError::new(StatusCode::INTERNAL_SERVER_ERROR, err))?;
+	return Ok(Response::builder()
+		.header(hyper::header::CONTENT_TYPE, "text/plain; version=0.0.4")
+		.body(Body::from(metrics))
+		.unwrap());
+}
+
+fn parse_range(
+	from: Option<Timestamp>,
+	to: Option<Timestamp>,
+) -> Result<(Timestamp, Timestamp), HttpError> {
+	let range = (from.unwrap_or(MIN_TIME), to.unwrap_or(MAX_TIME));
+	if range.0 > range.1 {
+		return Err(HttpError::new(
+			StatusCode::BAD_REQUEST,
+			"from must be less than to",
+		));
+	}
+	return Ok(range);
+}
+
+fn parse_tags(tags: &str) -> Vec<String> {
+	return tags
+		.split(',')
+		.filter(|tag| !tag.is_empty())
+		.map(|tag| tag.to_string())
+		.collect();
+}
+
+fn parse_query<T: DeserializeOwned>(req: &Request<Body>) -> Result<T, HttpError> {
+	serde_urlencoded::from_str(req.uri().query().unwrap_or(""))
+		.map_err(|err| HttpError::new(StatusCode::BAD_REQUEST, err))
+}
+
+fn json<T: Serialize>(value: &T) -> HandlerResult {
+	let body = serde_json::to_vec(value)
+		.map_err(|err| HttpError::new(StatusCode::INTERNAL_SERVER_ERROR, err))?;
+	return Ok(Response::builder()
+		.header(hyper::header::CONTENT_TYPE, "application/json")
+		.body(Body::from(body))
+		.unwrap());
+}
+
+#[cfg(test)]
+#[path = "tests/server.rs"]
+mod server_test;
@@ -0,0 +1,270 @@
+use super::*;
+use crate::tests;
+
+async fn get(storage: &Arc<Storage>, uri: &str) -> Result<(StatusCode, Vec<u8>), anyhow::Error> {
+	let req = Request::get(uri).body(Body::empty())?;
+	let resp = handle(Arc::clone(storage), req).await?;
+	let status = resp.status();
+	let body = hyper::body::to_bytes(resp.into_body()).await?;
+	return Ok((status, body.to_vec()));
+}
+
+async fn post(
+	storage: &Arc<Storage>,
+	uri: &str,
+	body: &str,
+) -> Result<(StatusCode, Vec<u8>), anyhow::Error> {
+	let req = Request::post(uri).body(Body::from(body.to_string()))?;
+	let resp = handle(Arc::clone(storage), req).await?;
+	let status = resp.status();
+	let body = hyper::body::to_bytes(resp.into_body()).await?;
+	return Ok((status, body.to_vec()));
+}
+
+#[test]
+fn tags() -> Result<(), anyhow::Error> {
+	tests::async_basic!(data_dir, {
+		let config = Config {
+			data_dir: data_dir.to_path_buf(),
+			max_active_size: 100,
+			max_block_size: 1000,
+			..Default::default()
+		};
+		let (storage, stop) = Storage::new(config)?;
+		for (key, tags) in [("key0", ["host:a", "dc:1"]), ("key1", ["host:b", "dc:2"])] {
+			let tags = tags.iter().map(|tag| tag.to_string()).collect();
+			storage.push(key.to_string(), tags).await?;
+		}
+
+		let (status, body) = get(&storage, "/tags?prefix=host%3A").await?;
+		assert_eq!(status, StatusCode::OK);
+		assert_eq!(
+			serde_json::from_slice::<Vec<String>>(&body)?,
+			["host:a", "host:b"]
+		);
+
+		let (_, body) = get(&storage, "/tags?limit=1&after=dc%3A1").await?;
+		assert_eq!(serde_json::from_slice::<Vec<String>>(&body)?, ["dc:2"]);
+
+		let (status, _) = get(&storage, "/tags?limit=abc").await?;
+		assert_eq!(status, StatusCode::BAD_REQUEST);
+
+		let (status, _) = get(&storage, "/unknown").await?;
+		assert_eq!(status, StatusCode::NOT_FOUND);
+
+		stop.await?;
+
+		Ok(())
+	})
+}
+
+#[test]
+fn query() -> Result<(), anyhow::Error> {
+	tests::async_basic!(data_dir, {
+		let config = Config {
+			data_dir: data_dir.to_path_buf(),
+			max_active_size: 100,
+			max_block_size: 1000,
+			..Default::default()
+		};
+		let (storage, stop) = Storage::new(config)?;
+		for (key, tags) in [("key0", ["host:a", "dc:1"]), ("key1", ["host:b", "dc:1"])] {
+			let tags = tags.iter().map(|tag| tag.to_string()).collect();
+			storage.push(key.to_string(), tags).await?;
+		}
+
+		let keys = |body: Vec<u8>| -> Result<Vec<String>, anyhow::Error> {
+			let matches: Vec<serde_json::Value> = serde_json::from_slice(&body)?;
+			Ok(matches
+				.iter()
+				.map(|m| m["key"].as_str().unwrap().to_string())
+				.collect())
+		};
+
+		let (status, body) = get(&storage, "/query?tags=dc%3A1").await?;
+		assert_eq!(status, StatusCode::OK);
+		assert_eq!(keys(body)?, ["key1", "key0"]);
+
+		let (_, body) = get(&storage, "/query?tags=dc%3A1,host%3Aa").await?;
+		assert_eq!(keys(body)?, ["key0"]);
+
+		let (_, body) = get(&storage, "/query?tags=dc%3A1&limit=1").await?;
+		assert_eq!(keys(body)?, ["key1"]);
+
+		let (status, _) = get(&storage, "/query?from=10&to=1").await?;
+		assert_eq!(status, StatusCode::BAD_REQUEST);
+
+		stop.await?;
+
+		Ok(())
+	})
+}
+
+#[test]
+fn push() -> Result<(), anyhow::Error> {
+	tests::async_basic!(data_dir, {
+		let config = Config {
+			data_dir: data_dir.to_path_buf(),
+			max_active_size: 1,
+			max_block_size: 1000,
+			max_pending_blocks: 1,
+			reject_on_backpressure: true,
+			..Default::default()
+		};
+		let (storage, stop) = Storage::new(config)?;
+		tokio::task::yield_now().await;
+
+		let body = r#"[{"key": "key0", "tags": ["dc:1"]}, {"key": "key1", "tags": ["dc:1"]}]"#;
+		let (status, _) = post(&storage, "/push", body).await?;
+		assert_eq!(status, StatusCode::NO_CONTENT);
+
+		let (_, body) = get(&storage, "/query?tags=dc%3A1").await?;
+		assert_eq!(
+			serde_json::from_slice::<Vec<serde_json::Value>>(&body)?.len(),
+			2
+		);
+
+		let (status, _) = post(&storage, "/push", "{").await?;
+		assert_eq!(status, StatusCode::BAD_REQUEST);
+
+		// save worker can still drain sealed blocks in the background,
+		// but can't keep up forever, so the storage becomes overloaded
+		let body = r#"[{"key": "key2", "tags": ["dc:2"]}]"#;
+		let mut statuses = Vec::default();
+		for _ in 0..100 {
+			statuses.push(post(&storage, "/push", body).await?.0);
+			if statuses.last() == Some(&StatusCode::TOO_MANY_REQUESTS) {
+				break;
+			}
+		}
+		assert_eq!(statuses.last(), Some(&StatusCode::TOO_MANY_REQUESTS));
+
+		stop.await?;
+
+		Ok(())
+	})
+}
+
+#[test]
+fn metrics() -> Result<(), anyhow::Error> {
+	tests::async_basic!(data_dir, {
+		let config = Config {
+			data_dir: data_dir.to_path_buf(),
+			max_active_size: 100,
+			max_block_size: 1000,
+			..Default::default()
+		};
+		let (storage, stop) = Storage::new(config)?;
+		let (status, _) = post(&storage, "/push", r#"[{"key": "key0", "tags": ["dc:1"]}]"#).await?;
+		assert_eq!(status, StatusCode::NO_CONTENT);
+		get(&storage, "/query?tags=dc%3A1").await?;
+
+		let (status, body) = get(&storage, "/metrics").await?;
+		assert_eq!(status, StatusCode::OK);
+		let body = String::from_utf8(body)?;
+		assert!(body.contains("\ntagged_documents_total 1\n"));
+		assert!(body.contains("\ntagged_request_duration_seconds_count{op=\"query\"} 1\n"));
+
+		stop.await?;
+
+		Ok(())
+	})
+}
+
+#[test]
+fn stats() -> Result<(), anyhow::Error> {
+	tests::async_basic!(data_dir, {
+		let config = Config {
+			data_dir: data_dir.to_path_buf(),
+			max_active_size: 100,
+			max_block_size: 1000,
+			..Default::default()
+		};
+		let (storage, stop) = Storage::new(config)?;
+		storage
+			.push("key0".to_string(), vec!["dc:1".to_string()])
+			.await?;
+
+		let (status, body) = get(&storage, "/stats").await?;
+		assert_eq!(status, StatusCode::OK);
+		let stats: serde_json::Value = serde_json::from_slice(&body)?;
+		assert_eq!(stats["total"]["documents"], 1);
+		assert_eq!(stats["blocks"][0]["block_type"], "active");
+		assert_eq!(stats["blocks"][0]["tags"], 1);
+
+		stop.await?;
+
+		Ok(())
+	})
+}
+
+#[test]
+fn export() -> Result<(), anyhow::Error> {
+	tests::async_basic!(data_dir, {
+		let config = Config {
+			data_dir: data_dir.to_path_buf(),
+			max_active_size: 100,
+			max_block_size: 1000,
+			..Default::default()
+		};
+		let (storage, stop) = Storage::new(config)?;
+		for (key, tags) in [("key0", ["host:a", "dc:1"]), ("key1", ["host:b", "dc:1"])] {
+			let tags = tags.iter().map(|tag| tag.to_string()).collect();
+			storage.push(key.to_string(), tags).await?;
+		}
+
+		let (status, body) = get(&storage, "/export?tags=dc%3A1").await?;
+		assert_eq!(status, StatusCode::OK);
+		let records: Vec<_> = read_import(&body[..], Format::Jsonl).collect::<Result<_, _>>()?;
+		let keys: Vec<_> = records.iter().map(|record| record.key.as_str()).collect();
+		assert_eq!(keys, ["key0", "key1"]);
+		assert_eq!(records[1].tags, ["dc:1", "host:b"]);
+
+		let (status, body) = get(&storage, "/export?tags=host%3Ab&format=msgpack").await?;
+		assert_eq!(status, StatusCode::OK);
+		let record: Record = rmp_serde::from_slice(&body)?;
+		assert_eq!(record, records[1]);
+
+		let (status, _) = get(&storage, "/export?format=xml").await?;
+		assert_eq!(status, StatusCode::BAD_REQUEST);
+		let (status, _) = get(&storage, "/export?from=2&to=1").await?;
+		assert_eq!(status, StatusCode::BAD_REQUEST);
+
+		stop.await?;
+
+		Ok(())
+	})
+}
+
+#[test]
+fn payload() -> Result<(), anyhow::Error> {
+	tests::async_basic!(data_dir, {
+		let config = Config {
+			data_dir: data_dir.to_path_buf(),
+			max_active_size: 100,
+			max_block_size: 1000,
+			..Default::default()
+		};
+		let (storage, stop) = Storage::new(config)?;
+
+		let docs = r#"[
+			{"key": "key0", "tags": ["dc:1"], "payload": {"body": "text"}},
+			{"key": "key1", "tags": ["dc:1"]}
+		]"#;
+		let (status, _) = post(&storage, "/push", docs).await?;
+		assert_eq!(status, StatusCode::NO_CONTENT);
+
+		let (_, body) = get(&storage, "/query?tags=dc%3A1").await?;
+		let matches: Vec<serde_json::Value> = serde_json::from_slice(&body)?;
+		assert!(matches.iter().all(|m| m.get("payload").is_none()));
+
+		let (_, body) = get(&storage, "/query?tags=dc%3A1&payload=true").await?;
+		let matches: Vec<serde_json::Value> = serde_json::from_slice(&body)?;
+		assert_eq!(matches[0].get("payload"), None);
+		assert_eq!(matches[1]["payload"], serde_json::json!({"body": "text"}));
+
+		stop.await?;
+
+		Ok(())
+	})
+}
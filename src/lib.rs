@@ -0,0 +1,7 @@
+#![allow(clippy::needless_return, clippy::module_inception)]
+
+pub mod config;
+pub mod http;
+pub mod storage;
+#[cfg(test)]
+mod tests;
@@ -1,10 +1,29 @@
-mod storage;
-#[cfg(test)]
-mod tests;
+#![allow(clippy::needless_return)]
+
+use clap::Parser;
+use std::sync::Arc;
+use tagged::{config, http, storage};
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-	//let addr = ([127, 0, 0, 1], 3000).into();
-	//let server = Server::bind(&addr).serve();
+	env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+
+	let args = config::Args::parse();
+	let config = args.load_config()?;
+	log::info!(
+		"effective config:\nlisten = \"{}\"\n{}",
+		args.listen,
+		toml::to_string(&config)?
+	);
+
+	std::fs::create_dir_all(config.data_dir())?;
+	let (storage, stop) = storage::Storage::new(config)?;
+
+	http::serve(Arc::clone(&storage), args.listen, async {
+		tokio::signal::ctrl_c().await.ok();
+	})
+	.await?;
+
+	stop.await?;
 	return Result::Ok(());
 }
@@ -1,50 +1,211 @@
-use serde::{Deserialize, Serialize};
+use super::{bloom_words, Bloom, KeyColumn, PayloadColumn, TimestampColumn};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use std::{
 	collections::BTreeMap,
 	io::{Read, Seek, SeekFrom, Write},
-	sync::Arc,
+	ops::{Deref, Range},
+	sync::{Arc, RwLock},
 };
 
 pub type Index = u64;
 pub type Offset = u64;
 pub type Timestamp = u64;
 
-#[derive(Debug, Copy, Clone)]
+/// Milliseconds since the unix epoch
+pub fn current_time() -> Timestamp {
+	return std::time::SystemTime::now()
+		.duration_since(std::time::UNIX_EPOCH)
+		.unwrap()
+		.as_millis() as Timestamp;
+}
+
+/// Bytes taken by the data: keys, tag strings, postings, timestamps and payloads.
+/// The same size is accounted for the active and in memory blocks, so they're sealed and
+/// written on disk by the same thresholds.
+fn data_size<'a>(
+	tags: impl Iterator<Item = &'a String>,
+	keys: &[String],
+	postings: usize,
+	payloads: usize,
+) -> u64 {
+	let tags: usize = tags.map(|tag| tag.len()).sum();
+	let timestamps = keys.len() * std::mem::size_of::<Timestamp>();
+	let keys: usize = keys.iter().map(|key| key.len()).sum();
+	let postings = postings * std::mem::size_of::<Index>();
+	return (tags + keys + timestamps + postings + payloads) as u64;
+}
+
+/// Payload is stored as JSON, empty bytes if the document doesn't have it
+pub fn encode_payload(payload: Option<&serde_json::Value>) -> Vec<u8> {
+	// serializing a value can't fail, its map keys are always strings
+	return payload
+		.map(|payload| serde_json::to_vec(payload).unwrap())
+		.unwrap_or_default();
+}
+
+/// None for the documents without the payload
+pub fn decode_payload(payload: &[u8]) -> Option<serde_json::Value> {
+	if payload.is_empty() {
+		return None;
+	}
+	return serde_json::from_slice(payload).ok();
+}
+
+/// Sorted ids of the documents with the tag, shared with the block.
+/// Postings of the active block keep growing, so a reader sees only the first `len` of them.
+#[derive(Debug, Clone)]
+pub struct Postings {
+	index: Arc<Vec<Index>>,
+	len: usize,
+}
+
+impl Postings {
+	pub fn new(index: Arc<Vec<Index>>) -> Postings {
+		let len = index.len();
+		return Postings { index, len };
+	}
+
+	pub fn with_len(index: Arc<Vec<Index>>, len: usize) -> Postings {
+		debug_assert!(len <= index.len());
+		return Postings { index, len };
+	}
+}
+
+impl Deref for Postings {
+	type Target = [Index];
+
+	fn deref(&self) -> &[Index] {
+		&self.index[..self.len]
+	}
+}
+
+#[allow(dead_code)]
+#[derive(Debug, Copy, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
 pub enum BlockType {
 	File,
 	InMemory,
+	Active,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct BlockStats {
+	pub block_type: BlockType,
+	pub range: Option<(Timestamp, Timestamp)>,
+	pub documents: u64,
+	pub tags: u64,
+	// zero for the blocks, that aren't on disk
+	pub disk_size: u64,
+	pub loaded_indexes: u64,
+	// estimate of the memory taken by the loaded indexes and data
+	pub memory: u64,
+}
+
+/// Document with its timestamp, as it's stored in the block
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct Record {
+	pub key: String,
+	pub tags: Vec<String>,
+	pub timestamp: Timestamp,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub payload: Option<serde_json::Value>,
+}
+
+impl Record {
+	/// Bytes the document adds to the block, at most, as the tag strings are stored
+	/// once per block
+	pub fn size(&self) -> u64 {
+		return data_size(
+			self.tags.iter(),
+			std::slice::from_ref(&self.key),
+			self.tags.len(),
+			encode_payload(self.payload.as_ref()).len(),
+		);
+	}
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Column {
+	Tags,
+	Keys,
+	Timestamps,
+	Payloads,
 }
 
-pub trait SearchBlock {
+pub const COLUMNS: [Column; 4] = [
+	Column::Tags,
+	Column::Keys,
+	Column::Timestamps,
+	Column::Payloads,
+];
+
+#[allow(dead_code)]
+pub trait SearchBlock: Send + Sync {
 	fn get_tags(&self) -> &[String];
-	fn get_keys(&self) -> &[String];
+	fn get_key(&self, id: usize) -> String;
 	fn read_index(&mut self, id: usize) -> Result<(), anyhow::Error>;
-	fn try_get_index(&self, id: usize) -> Option<Arc<Vec<u64>>>;
+	fn try_get_index(&self, id: usize) -> Option<Postings>;
+	// columns must be loaded before calling the getters
+	fn is_loaded(&self, column: Column) -> bool;
+	fn read_column(&mut self, column: Column) -> Result<(), anyhow::Error>;
+	fn release_column(&mut self, column: Column);
+	// releases all columns and indexes
+	fn unload(&mut self);
 	fn get_type(&self) -> BlockType;
+	// none for empty blocks
+	fn get_range(&self) -> Option<(Timestamp, Timestamp)>;
+	// number of the documents
+	fn get_count(&self) -> usize;
+	// known without reading the index itself
+	fn get_index_len(&self, id: usize) -> u64;
+	fn get_stats(&self) -> BlockStats;
+	// false if the block definitely doesn't have the tag
+	fn may_contain(&self, tag: &str) -> bool;
+	// ids of the rows, that are inside the (inclusive) range
+	fn get_rows(&self, range: (Timestamp, Timestamp)) -> Range<usize>;
+	fn get_timestamp(&self, id: usize) -> Timestamp;
+	// none for the documents without the payload
+	fn get_payload(&self, id: usize) -> Option<serde_json::Value>;
 }
 
 #[derive(Deserialize, Serialize, Debug, Default, Clone, PartialEq)]
 pub struct BlockHeader {
-	start: Offset,
-	tags: Offset,
-	keys: Offset,
-	timestamps: Offset,
-	index: Vec<Offset>,
-	from: Timestamp,
-	to: Timestamp,
+	// offsets of the sections inside the file, header itself is at the start
+	pub start: Offset,
+	pub tags: Offset,
+	pub keys: Offset,
+	pub timestamps: Offset,
+	pub index: Vec<Offset>,
+	// length of every index, so we can plan queries before reading them
+	pub lengths: Vec<u64>,
+	// filter over tags, so we can skip the block without looking at the tags
+	pub bloom: Bloom,
+	pub from: Timestamp,
+	pub to: Timestamp,
+	// number of documents
+	pub count: u64,
 	// block size inside file
-	size: u64,
+	pub size: u64,
+	// zero, if no document has the payload. older blocks don't have it at all
+	#[serde(default)]
+	pub payloads: Offset,
 }
 
 /// upper bound of size of header on disk
 fn header_size(size: usize) -> Offset {
 	// (struct byte) +
 	// (4 * u64 = start + tags + keys + timestamps) +
-	// (max array overhead) +
-	// (size * u64) +
-	// (2 * u64 = from + to)
-	// (1 * u64 = size)
-	return 1 + 4 * 9 + 5 + size as Offset * 9 + 2 * 9 + 9;
+	// 2 * ((max array overhead) + (size * u64) = index + lengths) +
+	// (struct byte) + (max array overhead) + (bloom words * u64) + (u32 = bloom hashes) +
+	// (3 * u64 = from + to + count)
+	// (2 * u64 = size + payloads)
+	return 1
+		+ 4 * 9
+		+ 2 * (5 + size as Offset * 9)
+		+ 1 + 5
+		+ bloom_words(size) as Offset * 9
+		+ 5 + 3 * 9
+		+ 2 * 9;
 }
 
 #[allow(dead_code)]
@@ -55,60 +216,100 @@ impl BlockHeader {
 	) -> Result<BlockHeader, anyhow::Error> {
 		input.seek(SeekFrom::Start(start))?;
 		let header: BlockHeader = rmp_serde::from_read(input)?;
-		debug_assert_eq!(header.start, start);
+		if header.start != start {
+			return Err(anyhow::anyhow!(
+				"header at {} says it starts at {}",
+				start,
+				header.start
+			));
+		}
 		return Ok(header);
 	}
 
-	pub fn read_meta<T: Write + Read + Seek>(
-		self,
-		mut file: T,
-	) -> Result<BlockFile<T>, anyhow::Error> {
-		file.seek(SeekFrom::Start(self.tags))?;
-		let tags = rmp_serde::from_read(&mut file)?;
-		file.seek(SeekFrom::Start(self.keys))?;
-		let keys = rmp_serde::from_read(&mut file)?;
-		file.seek(SeekFrom::Start(self.timestamps))?;
-		let timestamps = rmp_serde::from_read(&mut file)?;
+	// nothing except the header is loaded
+	pub fn open<T: Write + Read + Seek>(self, file: T) -> BlockFile<T> {
 		let indexes = self.index.len();
-		let block = BlockFile {
+		return BlockFile {
 			file,
 			header: self,
-			data: BlockData {
-				tags,
-				keys,
-				timestamps,
-				index: vec![Default::default(); indexes],
-			},
+			tags: None,
+			keys: None,
+			timestamps: None,
+			payloads: None,
+			index: vec![Default::default(); indexes],
 		};
-		return Ok(block);
 	}
 }
 
-#[derive(Debug, Default, PartialEq)]
+/// All documents of the block in the time order, every column and index is loaded
+pub fn read_records(block: &mut dyn SearchBlock) -> Result<Vec<Record>, anyhow::Error> {
+	for column in COLUMNS {
+		if !block.is_loaded(column) {
+			block.read_column(column)?;
+		}
+	}
+	let count = block.get_rows((0, Timestamp::MAX)).len();
+	let mut tags = vec![Vec::default(); count];
+	for id in 0..block.get_tags().len() {
+		if block.try_get_index(id).is_none() {
+			block.read_index(id)?;
+		}
+		let tag = &block.get_tags()[id];
+		for doc in block.try_get_index(id).unwrap().iter() {
+			let doc_tags: &mut Vec<String> = tags
+				.get_mut(*doc as usize)
+				.ok_or_else(|| anyhow::anyhow!("posting {} of {} is out of range", doc, tag))?;
+			doc_tags.push(tag.clone());
+		}
+	}
+	return Ok(tags
+		.into_iter()
+		.enumerate()
+		.map(|(id, tags)| Record {
+			key: block.get_key(id),
+			tags,
+			timestamp: block.get_timestamp(id),
+			payload: block.get_payload(id),
+		})
+		.collect());
+}
+
+fn rows(timestamps: &[Timestamp], range: (Timestamp, Timestamp)) -> Range<usize> {
+	let start = timestamps.partition_point(|ts| *ts < range.0);
+	let end = timestamps.partition_point(|ts| *ts <= range.1);
+	return start..std::cmp::max(start, end);
+}
+
+#[derive(Debug, Default, PartialEq, Clone)]
 pub struct BlockData {
 	tags: Vec<String>,
 	keys: Vec<String>,
 	timestamps: Vec<Timestamp>,
 	index: Vec<Option<Arc<Vec<Index>>>>,
+	// empty if no document has the payload, otherwise one for every document
+	payloads: Vec<Vec<u8>>,
 }
 
 impl BlockData {
+	// the block is returned on errors, so the write can be retried
+	#[allow(dead_code, clippy::result_large_err)]
 	pub fn write<T: Read + Write + Seek>(
 		self,
 		mut file: T,
 	) -> Result<BlockFile<T>, (Self, anyhow::Error)> {
 		let result = self.write_impl(&mut file);
 		return match result {
-			Ok(header) => Ok(BlockFile {
-				file,
-				header,
-				data: self,
-			}),
+			Ok((header, keys, timestamps, payloads)) => Ok(BlockFile::from_data(
+				file, header, keys, timestamps, payloads, self,
+			)),
 			Err(err) => Err((self, err)),
 		};
 	}
 
-	fn write_impl(&self, output: impl Write + Seek) -> Result<BlockHeader, anyhow::Error> {
+	fn write_impl(
+		&self,
+		output: impl Write + Seek,
+	) -> Result<(BlockHeader, KeyColumn, TimestampColumn, PayloadColumn), anyhow::Error> {
 		let mut header = BlockHeader::default();
 		let header_size = header_size(self.index.len());
 
@@ -119,24 +320,34 @@ impl BlockData {
 		self.tags
 			.serialize(&mut rmp_serde::Serializer::new(&mut output))?;
 		header.keys = output.stream_position()?;
-		self.keys
-			.serialize(&mut rmp_serde::Serializer::new(&mut output))?;
+		let keys = KeyColumn::encode(&self.keys);
+		keys.serialize(&mut rmp_serde::Serializer::new(&mut output))?;
 		header.timestamps = output.stream_position()?;
-		self.timestamps
-			.serialize(&mut rmp_serde::Serializer::new(&mut output))?;
+		let timestamps = TimestampColumn::encode(&self.timestamps);
+		timestamps.serialize(&mut rmp_serde::Serializer::new(&mut output))?;
 
 		for ind in self.index.iter() {
 			header.index.push(output.stream_position()?);
 			let ind = ind.as_ref().ok_or(anyhow::anyhow!(
 				"all indexes must be loaded to save the block"
 			))?;
+			header.lengths.push(ind.len() as u64);
 			ind.serialize(&mut rmp_serde::Serializer::new(&mut output))?;
 		}
+		// after the indexes, so the queries don't read over the payloads
+		let payloads = PayloadColumn::encode(&self.payloads);
+		if !payloads.is_empty() {
+			header.payloads = output.stream_position()?;
+			payloads.serialize(&mut rmp_serde::Serializer::new(&mut output))?;
+		}
+
+		header.bloom = Bloom::from_items(self.tags.iter());
 
 		let end = output.stream_position()?;
 		header.size = end - header.start;
 		header.from = self.timestamps.first().cloned().unwrap_or(0);
 		header.to = self.timestamps.last().cloned().unwrap_or(0);
+		header.count = self.keys.len() as u64;
 
 		output.seek(SeekFrom::Start(header.start))?;
 		header.serialize(&mut rmp_serde::Serializer::new(&mut output))?;
@@ -146,7 +357,7 @@ impl BlockData {
 		);
 		output.seek(SeekFrom::Start(header.start + header.size))?;
 
-		return Ok(header);
+		return Ok((header, keys, timestamps, payloads));
 	}
 
 	pub fn merge(mut self, mut other: BlockData) -> BlockData {
@@ -188,19 +399,11 @@ impl BlockData {
 			.index
 			.into_iter()
 			.map(unwrap_index)
-			.map(|index| {
-				index.map(|index| {
-					Arc::try_unwrap(index)
-						.unwrap()
-						.into_iter()
-						.map(|x| x + self.keys.len() as Index)
-				})
-			})
 			.zip(other.tags)
 			.for_each(|(index, tag)| {
-				Arc::get_mut(index_map.entry(tag).or_default())
-					.unwrap()
-					.extend(index.unwrap());
+				// indexes can still be shared with the block list snapshots, so copy on write
+				Arc::make_mut(index_map.entry(tag).or_default())
+					.extend(index.unwrap().iter().map(|x| x + self.keys.len() as Index));
 			});
 
 		self.tags = Vec::with_capacity(index_map.len());
@@ -210,12 +413,54 @@ impl BlockData {
 			self.index.push(Some(v));
 		}
 
+		if !self.payloads.is_empty() || !other.payloads.is_empty() {
+			self.payloads.resize(self.keys.len(), Vec::default());
+			other.payloads.resize(other.keys.len(), Vec::default());
+			self.payloads.append(&mut other.payloads);
+		}
 		self.keys.append(&mut other.keys);
 		self.timestamps.append(&mut other.timestamps);
 
 		return self;
 	}
 
+	/// Builds the block from the documents in the time order
+	pub fn from_records(records: Vec<Record>) -> BlockData {
+		debug_assert!(records.windows(2).all(|x| x[0].timestamp <= x[1].timestamp));
+		let mut index: BTreeMap<String, Vec<Index>> = BTreeMap::default();
+		let mut keys = Vec::with_capacity(records.len());
+		let mut timestamps = Vec::with_capacity(records.len());
+		let mut payloads = Vec::default();
+		if records.iter().any(|record| record.payload.is_some()) {
+			payloads = records
+				.iter()
+				.map(|record| encode_payload(record.payload.as_ref()))
+				.collect();
+		}
+		for (id, mut record) in records.into_iter().enumerate() {
+			// postings must be strictly sorted
+			record.tags.sort_unstable();
+			record.tags.dedup();
+			for tag in record.tags {
+				index.entry(tag).or_default().push(id as Index);
+			}
+			keys.push(record.key);
+			timestamps.push(record.timestamp);
+		}
+		let mut data = BlockData {
+			tags: Vec::with_capacity(index.len()),
+			keys,
+			timestamps,
+			index: Vec::with_capacity(index.len()),
+			payloads,
+		};
+		for (tag, postings) in index {
+			data.tags.push(tag);
+			data.index.push(Some(Arc::new(postings)));
+		}
+		return data;
+	}
+
 	pub fn range(&self) -> (Timestamp, Timestamp) {
 		// we shouldn't have empty blocks at all
 		(
@@ -224,8 +469,26 @@ impl BlockData {
 		)
 	}
 
-	pub fn release(&mut self, ind: usize) {
-		self.index[ind] = None;
+	/// All indexes must be loaded
+	fn size(&self) -> u64 {
+		let postings = self
+			.index
+			.iter()
+			.map(|index| index.as_ref().map(|index| index.len()).unwrap_or(0))
+			.sum();
+		let payloads = self.payloads.iter().map(|payload| payload.len()).sum();
+		return data_size(self.tags.iter(), &self.keys, postings, payloads);
+	}
+
+	fn payload(&self, id: usize) -> Option<serde_json::Value> {
+		return decode_payload(self.payloads.get(id).map(Vec::as_slice).unwrap_or_default());
+	}
+
+	fn try_range(&self) -> Option<(Timestamp, Timestamp)> {
+		if self.timestamps.is_empty() {
+			return None;
+		}
+		return Some(self.range());
 	}
 }
 
@@ -233,21 +496,47 @@ impl BlockData {
 pub struct BlockFile<T> {
 	file: T,
 	header: BlockHeader,
-	data: BlockData,
+	// columns are loaded on demand and can be released, like indexes
+	tags: Option<Vec<String>>,
+	keys: Option<KeyColumn>,
+	timestamps: Option<TimestampColumn>,
+	payloads: Option<PayloadColumn>,
+	index: Vec<Option<Arc<Vec<Index>>>>,
 }
 
 #[allow(dead_code)]
 impl<T: Read + Write + Seek> BlockFile<T> {
-	pub fn read_all(&mut self) -> Result<(), anyhow::Error> {
-		for i in 0..self.data.index.len() {
-			self.read_index(i)?;
+	fn from_data(
+		file: T,
+		header: BlockHeader,
+		keys: KeyColumn,
+		timestamps: TimestampColumn,
+		payloads: PayloadColumn,
+		data: BlockData,
+	) -> BlockFile<T> {
+		BlockFile {
+			file,
+			header,
+			tags: Some(data.tags),
+			keys: Some(keys),
+			timestamps: Some(timestamps),
+			payloads: Some(payloads),
+			index: data.index,
 		}
-		return Ok(());
+	}
+
+	/// Bytes taken by the block on disk
+	pub fn size(&self) -> u64 {
+		return self.header.size;
+	}
+
+	pub fn header(&self) -> &BlockHeader {
+		return &self.header;
 	}
 
 	pub fn update_index(&mut self, id: usize) -> Result<(), anyhow::Error> {
 		self.file.seek(SeekFrom::Start(self.header.index[id]))?;
-		self.data.index[id]
+		self.index[id]
 			.as_ref()
 			.ok_or(anyhow::anyhow!("index must be loaded to update it"))?
 			.serialize(&mut rmp_serde::Serializer::new(&mut self.file))?;
@@ -255,62 +544,358 @@ impl<T: Read + Write + Seek> BlockFile<T> {
 	}
 
 	pub fn range(&self) -> (Timestamp, Timestamp) {
-		return self.data.range();
+		return (self.header.from, self.header.to);
 	}
 
+	// columns and indexes, that aren't loaded, are left empty
 	pub fn release_all(self) -> (T, BlockHeader, BlockData) {
-		return (self.file, self.header, self.data);
+		let data = BlockData {
+			tags: self.tags.unwrap_or_default(),
+			keys: self.keys.map(|keys| keys.decode_all()).unwrap_or_default(),
+			timestamps: self
+				.timestamps
+				.map(|timestamps| timestamps.decode_all())
+				.unwrap_or_default(),
+			index: self.index,
+			payloads: self
+				.payloads
+				.map(|payloads| payloads.decode_all())
+				.unwrap_or_default(),
+		};
+		return (self.file, self.header, data);
 	}
 
 	pub fn release(&mut self, ind: usize) {
-		self.data.release(ind);
+		self.index[ind] = None;
+	}
+
+	fn read_at<V: DeserializeOwned>(&mut self, offset: Offset) -> Result<V, anyhow::Error> {
+		self.file.seek(SeekFrom::Start(offset))?;
+		return Ok(rmp_serde::from_read(&mut self.file)?);
+	}
+}
+
+#[allow(dead_code)]
+impl<T: Read + Write + Seek + Send + Sync> BlockFile<T> {
+	pub fn read_all(&mut self) -> Result<(), anyhow::Error> {
+		for column in COLUMNS {
+			self.read_column(column)?;
+		}
+		for i in 0..self.index.len() {
+			self.read_index(i)?;
+		}
+		return Ok(());
+	}
+
+	/// Problems with the block consistency, the block is fully loaded to check it.
+	/// `file_len` is the length of the file with the block.
+	pub fn verify(&mut self, file_len: u64) -> Vec<String> {
+		let mut problems = Vec::default();
+		let header = &self.header;
+		let end = header.start.saturating_add(header.size);
+		if end > file_len {
+			problems.push(format!(
+				"block ends at {}, after the end of the file {}",
+				end, file_len
+			));
+		}
+		// sections go one after another inside the block
+		let mut prev = ("header", header.start);
+		let sections = [
+			("tags", header.tags),
+			("keys", header.keys),
+			("timestamps", header.timestamps),
+		];
+		let payloads = Some(("payloads", header.payloads)).filter(|(_, offset)| *offset != 0);
+		for (name, offset) in sections
+			.into_iter()
+			.chain(header.index.iter().map(|offset| ("index", *offset)))
+			.chain(payloads)
+		{
+			if offset <= prev.1 || offset > end {
+				problems.push(format!(
+					"{} offset {} is out of order after {} offset {}, block end {}",
+					name, offset, prev.0, prev.1, end
+				));
+			}
+			prev = (name, offset);
+		}
+		if header.lengths.len() != header.index.len() {
+			problems.push(format!(
+				"{} index lengths for {} indexes",
+				header.lengths.len(),
+				header.index.len()
+			));
+		}
+		if header.from > header.to {
+			problems.push(format!("range {}..{} is reversed", header.from, header.to));
+		}
+		if !problems.is_empty() {
+			// sections can't be read with the broken header
+			return problems;
+		}
+
+		if let Err(err) = self.read_all() {
+			problems.push(format!("can't read the block: {}", err));
+			return problems;
+		}
+		let header = &self.header;
+		let count = header.count as usize;
+
+		let tags = self.tags.as_ref().unwrap();
+		if tags.len() != header.index.len() {
+			problems.push(format!(
+				"{} tags for {} indexes",
+				tags.len(),
+				header.index.len()
+			));
+		}
+		if let Some(pair) = tags.windows(2).find(|pair| pair[0] >= pair[1]) {
+			problems.push(format!("tags aren't sorted: {:?}", pair));
+		}
+		if let Some(tag) = tags.iter().find(|tag| !header.bloom.contains(tag)) {
+			problems.push(format!("bloom filter doesn't have the tag {:?}", tag));
+		}
+
+		match self.keys.as_ref().unwrap().try_decode_all() {
+			Ok(keys) if keys.len() != count => {
+				problems.push(format!("{} keys for {} documents", keys.len(), count))
+			}
+			Ok(_) => {}
+			Err(err) => problems.push(format!("broken keys: {}", err)),
+		}
+		// broken timestamps aren't checked any further
+		let timestamps = match self.timestamps.as_ref().unwrap().try_decode_all() {
+			Ok(timestamps) if timestamps.len() != count => {
+				problems.push(format!(
+					"{} timestamps for {} documents",
+					timestamps.len(),
+					count
+				));
+				timestamps
+			}
+			Ok(timestamps) => timestamps,
+			Err(err) => {
+				problems.push(format!("broken timestamps: {}", err));
+				Vec::default()
+			}
+		};
+		let payloads = self.payloads.as_ref().unwrap();
+		if header.payloads != 0 && payloads.len() != count {
+			problems.push(format!(
+				"{} payloads for {} documents",
+				payloads.len(),
+				count
+			));
+		}
+		if let Err(err) = payloads.validate() {
+			problems.push(format!("broken payloads: {}", err));
+		} else if let Some(id) = (0..payloads.len()).find(|id| {
+			let payload = payloads.get(*id);
+			!payload.is_empty() && decode_payload(payload).is_none()
+		}) {
+			problems.push(format!("payload of the document {} isn't JSON", id));
+		}
+		if let Some(pair) = timestamps.windows(2).find(|pair| pair[0] > pair[1]) {
+			problems.push(format!("timestamps go back: {:?}", pair));
+		}
+		if let (Some(first), Some(last)) = (timestamps.first(), timestamps.last()) {
+			if (*first, *last) != (header.from, header.to) {
+				problems.push(format!(
+					"timestamps {}..{} don't match the header range {}..{}",
+					first, last, header.from, header.to
+				));
+			}
+		}
+
+		for (i, index) in self.index.iter().enumerate() {
+			let tag = tags.get(i).map(|tag| tag.as_str()).unwrap_or("");
+			let index = index.as_ref().unwrap();
+			if header.lengths.get(i) != Some(&(index.len() as u64)) {
+				problems.push(format!(
+					"index of {:?} has {} postings instead of {:?}",
+					tag,
+					index.len(),
+					header.lengths.get(i)
+				));
+			}
+			if let Some(id) = index.iter().find(|id| **id as usize >= count) {
+				problems.push(format!(
+					"index of {:?} has posting {} for {} documents",
+					tag, id, count
+				));
+			}
+			if index.windows(2).any(|pair| pair[0] >= pair[1]) {
+				problems.push(format!("index of {:?} isn't sorted", tag));
+			}
+		}
+		return problems;
 	}
 }
 
-impl<T: Read + Write + Seek> SearchBlock for BlockFile<T> {
+impl<T: Read + Write + Seek + Send + Sync> SearchBlock for BlockFile<T> {
 	fn get_tags(&self) -> &[String] {
-		&self.data.tags
+		self.tags.as_deref().expect("tags must be loaded")
 	}
 
-	fn get_keys(&self) -> &[String] {
-		&self.data.keys
+	fn get_key(&self, id: usize) -> String {
+		self.keys.as_ref().expect("keys must be loaded").get(id)
 	}
 
-	fn try_get_index(&self, id: usize) -> Option<Arc<Vec<u64>>> {
-		return self.data.index[id].as_ref().map(|x| Arc::clone(&x));
+	fn try_get_index(&self, id: usize) -> Option<Postings> {
+		return self.index[id]
+			.as_ref()
+			.map(|index| Postings::new(Arc::clone(index)));
 	}
 
 	fn read_index(&mut self, id: usize) -> Result<(), anyhow::Error> {
-		match self.data.index[id] {
+		match self.index[id] {
 			Some(_) => Ok(()),
 			None => {
-				self.file.seek(SeekFrom::Start(self.header.index[id]))?;
-				self.data.index[id] = Some(Arc::new(rmp_serde::from_read(&mut self.file)?));
+				self.index[id] = Some(Arc::new(self.read_at(self.header.index[id])?));
 				Ok(())
 			}
 		}
 	}
 
+	fn is_loaded(&self, column: Column) -> bool {
+		match column {
+			Column::Tags => self.tags.is_some(),
+			Column::Keys => self.keys.is_some(),
+			Column::Timestamps => self.timestamps.is_some(),
+			Column::Payloads => self.payloads.is_some(),
+		}
+	}
+
+	fn read_column(&mut self, column: Column) -> Result<(), anyhow::Error> {
+		if self.is_loaded(column) {
+			return Ok(());
+		}
+		match column {
+			Column::Tags => self.tags = Some(self.read_at(self.header.tags)?),
+			Column::Keys => self.keys = Some(self.read_at(self.header.keys)?),
+			Column::Timestamps => self.timestamps = Some(self.read_at(self.header.timestamps)?),
+			Column::Payloads if self.header.payloads == 0 => {
+				self.payloads = Some(PayloadColumn::default())
+			}
+			Column::Payloads => self.payloads = Some(self.read_at(self.header.payloads)?),
+		}
+		return Ok(());
+	}
+
+	fn release_column(&mut self, column: Column) {
+		match column {
+			Column::Tags => self.tags = None,
+			Column::Keys => self.keys = None,
+			Column::Timestamps => self.timestamps = None,
+			Column::Payloads => self.payloads = None,
+		}
+	}
+
+	fn unload(&mut self) {
+		for column in COLUMNS {
+			self.release_column(column);
+		}
+		for i in 0..self.index.len() {
+			self.release(i);
+		}
+	}
+
 	fn get_type(&self) -> BlockType {
 		BlockType::File
 	}
+
+	fn get_range(&self) -> Option<(Timestamp, Timestamp)> {
+		if self.header.count == 0 {
+			return None;
+		}
+		return Some(self.range());
+	}
+
+	fn get_count(&self) -> usize {
+		self.header.count as usize
+	}
+
+	fn get_index_len(&self, id: usize) -> u64 {
+		self.header.lengths[id]
+	}
+
+	fn get_stats(&self) -> BlockStats {
+		let loaded = self.index.iter().flatten();
+		let postings: usize = loaded.clone().map(|index| index.len()).sum();
+		let tags: usize = self.tags.iter().flatten().map(|tag| tag.len()).sum();
+		let columns = tags as u64
+			+ self.keys.as_ref().map(KeyColumn::size).unwrap_or(0)
+			+ self
+				.timestamps
+				.as_ref()
+				.map(TimestampColumn::size)
+				.unwrap_or(0)
+			+ self.payloads.as_ref().map(PayloadColumn::size).unwrap_or(0);
+		BlockStats {
+			block_type: self.get_type(),
+			range: self.get_range(),
+			documents: self.header.count,
+			tags: self.header.index.len() as u64,
+			disk_size: self.header.size,
+			loaded_indexes: loaded.count() as u64,
+			memory: (postings * std::mem::size_of::<Index>()) as u64 + columns,
+		}
+	}
+
+	fn may_contain(&self, tag: &str) -> bool {
+		self.header.bloom.contains(tag)
+	}
+
+	fn get_rows(&self, range: (Timestamp, Timestamp)) -> Range<usize> {
+		// whole block is inside the range, so we don't need timestamps
+		if range.0 <= self.header.from && self.header.to <= range.1 {
+			return 0..self.header.count as usize;
+		}
+		return self
+			.timestamps
+			.as_ref()
+			.expect("timestamps must be loaded")
+			.rows(range);
+	}
+
+	fn get_timestamp(&self, id: usize) -> Timestamp {
+		self.timestamps
+			.as_ref()
+			.expect("timestamps must be loaded")
+			.get(id)
+	}
+
+	fn get_payload(&self, id: usize) -> Option<serde_json::Value> {
+		decode_payload(
+			self.payloads
+				.as_ref()
+				.expect("payloads must be loaded")
+				.get(id),
+		)
+	}
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct InMemoryBlock {
 	data: BlockData,
 	size: u64,
 }
 
 impl InMemoryBlock {
+	pub fn from_data(data: BlockData) -> InMemoryBlock {
+		let size = data.size();
+		return InMemoryBlock { data, size };
+	}
+
 	pub fn merge(self, other: InMemoryBlock) -> InMemoryBlock {
+		// tags, that both blocks have, are stored once
 		let data = self.data.merge(other.data);
-		return InMemoryBlock {
-			data,
-			size: self.size + other.size,
-		};
+		let size = data.size();
+		return InMemoryBlock { data, size };
 	}
 
+	#[allow(dead_code, clippy::result_large_err)]
 	pub fn write<T: Write + Read + Seek>(
 		self,
 		file: T,
@@ -326,6 +911,15 @@ impl InMemoryBlock {
 		});
 	}
 
+	/// Writes the block without consuming it, only the header of the written block is loaded.
+	pub fn write_unloaded<T: Write + Read + Seek>(
+		&self,
+		mut file: T,
+	) -> Result<BlockFile<T>, anyhow::Error> {
+		let (header, ..) = self.data.write_impl(&mut file)?;
+		return Ok(header.open(file));
+	}
+
 	pub fn size(&self) -> u64 {
 		return self.size;
 	}
@@ -340,13 +934,15 @@ impl SearchBlock for InMemoryBlock {
 		&self.data.tags
 	}
 
-	fn get_keys(&self) -> &[String] {
-		&self.data.keys
+	fn get_key(&self, id: usize) -> String {
+		self.data.keys[id].clone()
 	}
 
-	fn try_get_index(&self, id: usize) -> Option<Arc<Vec<u64>>> {
+	fn try_get_index(&self, id: usize) -> Option<Postings> {
 		// we want to force check, that in inmemoryblock we always have indexes
-		Some(Arc::clone(self.data.index[id].as_ref().unwrap()))
+		Some(Postings::new(Arc::clone(
+			self.data.index[id].as_ref().unwrap(),
+		)))
 	}
 
 	fn read_index(&mut self, _: usize) -> Result<(), anyhow::Error> {
@@ -355,43 +951,220 @@ impl SearchBlock for InMemoryBlock {
 		))
 	}
 
+	fn is_loaded(&self, _: Column) -> bool {
+		true
+	}
+
+	fn read_column(&mut self, _: Column) -> Result<(), anyhow::Error> {
+		Ok(())
+	}
+
+	// in memory block can't be unloaded
+	fn release_column(&mut self, _: Column) {}
+
+	fn unload(&mut self) {}
+
 	fn get_type(&self) -> BlockType {
 		BlockType::InMemory
 	}
+
+	fn get_range(&self) -> Option<(Timestamp, Timestamp)> {
+		self.data.try_range()
+	}
+
+	fn get_count(&self) -> usize {
+		self.data.keys.len()
+	}
+
+	fn get_index_len(&self, id: usize) -> u64 {
+		self.data.index[id].as_ref().unwrap().len() as u64
+	}
+
+	fn get_stats(&self) -> BlockStats {
+		BlockStats {
+			block_type: self.get_type(),
+			range: self.get_range(),
+			documents: self.data.keys.len() as u64,
+			tags: self.data.tags.len() as u64,
+			disk_size: 0,
+			loaded_indexes: self.data.index.len() as u64,
+			memory: self.size,
+		}
+	}
+
+	fn may_contain(&self, tag: &str) -> bool {
+		self.data
+			.tags
+			.binary_search_by(|x| x.as_str().cmp(tag))
+			.is_ok()
+	}
+
+	fn get_rows(&self, range: (Timestamp, Timestamp)) -> Range<usize> {
+		rows(&self.data.timestamps, range)
+	}
+
+	fn get_timestamp(&self, id: usize) -> Timestamp {
+		self.data.timestamps[id]
+	}
+
+	fn get_payload(&self, id: usize) -> Option<serde_json::Value> {
+		self.data.payload(id)
+	}
 }
 
 #[derive(Debug, Default, Clone)]
 pub struct ActiveBlock {
-	index: BTreeMap<String, Vec<Index>>,
+	// postings are shared with the views, and are copied on write only if a view
+	// still holds them
+	index: BTreeMap<String, Arc<Vec<Index>>>,
 	keys: Vec<String>,
 	timestamps: Vec<Timestamp>,
+	// (seq, first id) of every push, seq orders pushes between the shards
+	runs: Vec<(u64, Index)>,
+	// sorted tags for the views, shared with them like the postings
+	sorted_tags: Arc<Vec<String>>,
+	// empty until the first document with the payload is pushed
+	payloads: Vec<Vec<u8>>,
 	size: u64,
 }
 
 impl ActiveBlock {
+	#[allow(dead_code)]
 	pub fn push(&mut self, key: String, tags: Vec<String>) {
-		self.size += tags.len() as u64;
+		let seq = self.runs.last().map(|run| run.0).unwrap_or(0);
+		self.push_at(seq, current_time(), key, tags);
+	}
+
+	/// Pushes the document as a part of the push `seq`.
+	/// Pushes with bigger `seq` must have bigger or the same `timestamp`.
+	pub fn push_at(&mut self, seq: u64, timestamp: Timestamp, key: String, tags: Vec<String>) {
+		self.push_with_payload(seq, timestamp, key, tags, Vec::default());
+	}
+
+	/// Like `push_at`, with the payload encoded by `encode_payload`
+	pub fn push_with_payload(
+		&mut self,
+		seq: u64,
+		timestamp: Timestamp,
+		key: String,
+		mut tags: Vec<String>,
+		payload: Vec<u8>,
+	) {
+		// postings must be strictly sorted
+		tags.sort_unstable();
+		tags.dedup();
+		self.size += data_size(
+			std::iter::empty(),
+			std::slice::from_ref(&key),
+			tags.len(),
+			payload.len(),
+		);
 
 		let id = self.keys.len() as Index;
+		if !payload.is_empty() || !self.payloads.is_empty() {
+			self.payloads.resize(self.keys.len(), Vec::default());
+			self.payloads.push(payload);
+		}
+		if self.runs.last().map(|run| run.0 != seq).unwrap_or(true) {
+			self.runs.push((seq, id));
+		}
 		self.keys.push(key);
 		self.timestamps.push(std::cmp::max(
-			std::time::SystemTime::now()
-				.duration_since(std::time::UNIX_EPOCH)
-				.unwrap()
-				.as_millis() as Timestamp,
-			self.timestamps.last().cloned().unwrap_or(0), // TODO: use ts from previous active block as starting point
+			timestamp,
+			self.timestamps.last().cloned().unwrap_or(0),
 		));
 		for tag in tags.into_iter() {
-			self.index.entry(tag).or_default().push(id);
+			match self.index.get_mut(&tag) {
+				Some(index) => Arc::make_mut(index).push(id),
+				None => {
+					self.size += tag.len() as u64;
+					let sorted = Arc::make_mut(&mut self.sorted_tags);
+					sorted.insert(sorted.partition_point(|x| *x < tag), tag.clone());
+					self.index.insert(tag, Arc::new(vec![id]));
+				}
+			}
 		}
 	}
 
+	/// Merges shards, that were filled in parallel, into one block in the push order
+	pub fn merge(shards: Vec<ActiveBlock>) -> ActiveBlock {
+		let mut shards: Vec<_> = shards
+			.into_iter()
+			.filter(|shard| !shard.keys.is_empty())
+			.collect();
+		if shards.len() <= 1 {
+			return shards.pop().unwrap_or_default();
+		}
+
+		// (seq, shard, start, end)
+		let mut runs = Vec::default();
+		for (i, shard) in shards.iter().enumerate() {
+			for (j, (seq, start)) in shard.runs.iter().enumerate() {
+				let end = shard
+					.runs
+					.get(j + 1)
+					.map(|run| run.1)
+					.unwrap_or(shard.keys.len() as Index);
+				runs.push((*seq, i, *start, end));
+			}
+		}
+		runs.sort_unstable();
+
+		let mut result = ActiveBlock::default();
+		let mut keys: Vec<_> = shards
+			.iter_mut()
+			.map(|shard| std::mem::take(&mut shard.keys).into_iter())
+			.collect();
+		let mut ids: Vec<Vec<Index>> = shards
+			.iter()
+			.map(|shard| vec![0; shard.timestamps.len()])
+			.collect();
+		let has_payloads = shards.iter().any(|shard| !shard.payloads.is_empty());
+		let mut payloads: Vec<_> = shards
+			.iter_mut()
+			.map(|shard| std::mem::take(&mut shard.payloads))
+			.collect();
+		for (seq, shard, start, end) in runs {
+			result.runs.push((seq, result.keys.len() as Index));
+			for id in start..end {
+				if has_payloads {
+					let payload = payloads[shard].get_mut(id as usize).map(std::mem::take);
+					result.payloads.push(payload.unwrap_or_default());
+				}
+				ids[shard][id as usize] = result.keys.len() as Index;
+				result.keys.push(keys[shard].next().unwrap());
+				result
+					.timestamps
+					.push(shards[shard].timestamps[id as usize]);
+			}
+		}
+		debug_assert!(result.timestamps.windows(2).all(|x| x[0] <= x[1]));
+
+		for (shard, ids) in shards.into_iter().zip(ids) {
+			for (tag, index) in shard.index {
+				Arc::make_mut(result.index.entry(tag).or_default())
+					.extend(index.iter().map(|id| ids[*id as usize]));
+			}
+		}
+		let mut postings = 0;
+		for index in result.index.values_mut() {
+			Arc::make_mut(index).sort_unstable();
+			postings += index.len();
+		}
+		result.sorted_tags = Arc::new(result.index.keys().cloned().collect());
+		let payloads = result.payloads.iter().map(|payload| payload.len()).sum();
+		// tags, that several shards have, are stored once
+		result.size = data_size(result.index.keys(), &result.keys, postings, payloads);
+
+		return result;
+	}
+
 	pub fn into_block(self) -> InMemoryBlock {
 		let mut tags = Vec::with_capacity(self.index.len());
 		let mut index = Vec::with_capacity(self.index.len());
 		for (k, v) in self.index {
 			tags.push(k);
-			index.push(Some(Arc::new(v)));
+			index.push(Some(v));
 		}
 		return InMemoryBlock {
 			data: BlockData {
@@ -399,6 +1172,7 @@ impl ActiveBlock {
 				keys: self.keys,
 				timestamps: self.timestamps,
 				index,
+				payloads: self.payloads,
 			},
 			size: self.size,
 		};
@@ -407,6 +1181,135 @@ impl ActiveBlock {
 	pub fn size(&self) -> u64 {
 		return self.size;
 	}
+
+	/// Timestamp of the oldest document, none for the empty block
+	pub fn first_timestamp(&self) -> Option<Timestamp> {
+		return self.timestamps.first().cloned();
+	}
+}
+
+/// Read only view of the active block, that sees only the rows pushed before it was taken.
+/// Active block is append only, so the view shares its tags and postings instead of copying.
+pub struct ActiveView {
+	block: Arc<RwLock<ActiveBlock>>,
+	tags: Arc<Vec<String>>,
+	// rows with ids from here were pushed after the view was taken
+	len: usize,
+}
+
+impl ActiveView {
+	pub fn new(block: Arc<RwLock<ActiveBlock>>) -> ActiveView {
+		let (tags, len) = {
+			let block = block.read().unwrap();
+			(Arc::clone(&block.sorted_tags), block.keys.len())
+		};
+		return ActiveView { block, tags, len };
+	}
+
+	/// Sequence number of the push, that added the row.
+	/// Rows of all shards sorted by (seq, id) are in the push order.
+	pub fn get_seq(&self, id: usize) -> u64 {
+		debug_assert!(id < self.len);
+		let block = self.block.read().unwrap();
+		let run = block.runs.partition_point(|run| run.1 as usize <= id);
+		return block.runs[run - 1].0;
+	}
+
+	fn index_len(&self, index: &[Index]) -> usize {
+		index.partition_point(|id| (*id as usize) < self.len)
+	}
+}
+
+impl SearchBlock for ActiveView {
+	fn get_tags(&self) -> &[String] {
+		&self.tags
+	}
+
+	fn get_key(&self, id: usize) -> String {
+		debug_assert!(id < self.len);
+		self.block.read().unwrap().keys[id].clone()
+	}
+
+	fn try_get_index(&self, id: usize) -> Option<Postings> {
+		let block = self.block.read().unwrap();
+		let index = &block.index[&self.tags[id]];
+		Some(Postings::with_len(Arc::clone(index), self.index_len(index)))
+	}
+
+	fn read_index(&mut self, _: usize) -> Result<(), anyhow::Error> {
+		Err(anyhow::anyhow!("shouldn't call read_index on active block"))
+	}
+
+	fn is_loaded(&self, _: Column) -> bool {
+		true
+	}
+
+	fn read_column(&mut self, _: Column) -> Result<(), anyhow::Error> {
+		Ok(())
+	}
+
+	fn release_column(&mut self, _: Column) {}
+
+	fn unload(&mut self) {}
+
+	fn get_type(&self) -> BlockType {
+		BlockType::Active
+	}
+
+	fn get_range(&self) -> Option<(Timestamp, Timestamp)> {
+		if self.len == 0 {
+			return None;
+		}
+		let block = self.block.read().unwrap();
+		Some((block.timestamps[0], block.timestamps[self.len - 1]))
+	}
+
+	fn get_count(&self) -> usize {
+		self.len
+	}
+
+	fn get_index_len(&self, id: usize) -> u64 {
+		let block = self.block.read().unwrap();
+		self.index_len(&block.index[&self.tags[id]]) as u64
+	}
+
+	// memory is shared with the rows pushed after the view was taken
+	fn get_stats(&self) -> BlockStats {
+		BlockStats {
+			block_type: self.get_type(),
+			range: self.get_range(),
+			documents: self.len as u64,
+			tags: self.tags.len() as u64,
+			disk_size: 0,
+			loaded_indexes: self.tags.len() as u64,
+			memory: self.block.read().unwrap().size(),
+		}
+	}
+
+	fn may_contain(&self, tag: &str) -> bool {
+		self.tags.binary_search_by(|x| x.as_str().cmp(tag)).is_ok()
+	}
+
+	fn get_rows(&self, range: (Timestamp, Timestamp)) -> Range<usize> {
+		rows(&self.block.read().unwrap().timestamps[..self.len], range)
+	}
+
+	fn get_timestamp(&self, id: usize) -> Timestamp {
+		debug_assert!(id < self.len);
+		self.block.read().unwrap().timestamps[id]
+	}
+
+	fn get_payload(&self, id: usize) -> Option<serde_json::Value> {
+		debug_assert!(id < self.len);
+		let block = self.block.read().unwrap();
+		decode_payload(
+			block
+				.payloads
+				.get(id)
+				.map(Vec::as_slice)
+				.unwrap_or_default(),
+		)
+	}
 }
 
 #[cfg(test)]
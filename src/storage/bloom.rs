@@ -0,0 +1,91 @@
+use serde::{Deserialize, Serialize};
+
+const BITS_PER_ITEM: usize = 10;
+// optimal for 10 bits per item, gives ~1% false positive rate
+const HASHES: u32 = 7;
+
+/// Bloom filter over strings, small enough to be kept in the block header.
+#[derive(Deserialize, Serialize, Debug, Default, Clone, PartialEq)]
+pub struct Bloom {
+	bits: Vec<u64>,
+	hashes: u32,
+}
+
+/// number of u64 words in the filter for `items` items
+pub fn bloom_words(items: usize) -> usize {
+	(items * BITS_PER_ITEM).div_ceil(64)
+}
+
+impl Bloom {
+	pub fn new(items: usize) -> Bloom {
+		Bloom {
+			bits: vec![0; bloom_words(items)],
+			hashes: HASHES,
+		}
+	}
+
+	// filter with all bits set, the largest one on disk
+	#[cfg(test)]
+	pub fn filled(items: usize) -> Bloom {
+		Bloom {
+			bits: vec![u64::MAX; bloom_words(items)],
+			hashes: u32::MAX,
+		}
+	}
+
+	pub fn from_items<'a>(items: impl ExactSizeIterator<Item = &'a String>) -> Bloom {
+		let mut bloom = Bloom::new(items.len());
+		for item in items {
+			bloom.insert(item);
+		}
+		return bloom;
+	}
+
+	pub fn insert(&mut self, item: &str) {
+		let size = self.bits.len() as u64 * 64;
+		if size == 0 {
+			return;
+		}
+		for bit in positions(item, self.hashes, size) {
+			self.bits[(bit / 64) as usize] |= 1 << (bit % 64);
+		}
+	}
+
+	/// false means the item was definitely not inserted
+	pub fn contains(&self, item: &str) -> bool {
+		let size = self.bits.len() as u64 * 64;
+		if size == 0 {
+			return false;
+		}
+		return positions(item, self.hashes, size)
+			.all(|bit| self.bits[(bit / 64) as usize] & (1 << (bit % 64)) != 0);
+	}
+}
+
+// double hashing: h1 + i * h2
+// the filter is saved on disk, so we can't use std hashers, which can change between releases
+fn positions(item: &str, hashes: u32, size: u64) -> impl Iterator<Item = u64> {
+	let h1 = fnv1a(item.as_bytes());
+	let h2 = mix(h1) | 1;
+	(0..hashes as u64).map(move |i| h1.wrapping_add(i.wrapping_mul(h2)) % size)
+}
+
+fn fnv1a(data: &[u8]) -> u64 {
+	let mut hash: u64 = 0xcbf29ce484222325;
+	for byte in data {
+		hash ^= *byte as u64;
+		hash = hash.wrapping_mul(0x100000001b3);
+	}
+	return hash;
+}
+
+// splitmix64 finalizer
+fn mix(mut x: u64) -> u64 {
+	x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+	x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+	return x ^ (x >> 31);
+}
+
+#[cfg(test)]
+#[path = "tests/bloom.rs"]
+mod bloom_test;
@@ -0,0 +1,404 @@
+use super::Timestamp;
+use serde::{Deserialize, Serialize};
+use std::ops::Range;
+
+// every RESTART_INTERVAL key is stored in full
+const RESTART_INTERVAL: usize = 16;
+const TIMESTAMP_CHUNK: usize = 128;
+
+/// Keys compressed with front coding: every key is stored as the length of the prefix,
+/// shared with the previous key, and the rest of it.
+/// Keys are split into chunks, that start with the full key,
+/// so any key can be decoded without decoding the previous chunks.
+#[derive(Deserialize, Serialize, Debug, Default, Clone, PartialEq)]
+pub struct KeyColumn {
+	count: u64,
+	// offset of every chunk in data
+	restarts: Vec<u64>,
+	#[serde(with = "serde_bytes")]
+	data: Vec<u8>,
+}
+
+#[allow(dead_code)]
+impl KeyColumn {
+	pub fn encode(keys: &[String]) -> KeyColumn {
+		let mut column = KeyColumn {
+			count: keys.len() as u64,
+			restarts: Vec::with_capacity(keys.len().div_ceil(RESTART_INTERVAL)),
+			data: Vec::default(),
+		};
+		let mut prev: &[u8] = &[];
+		for (i, key) in keys.iter().enumerate() {
+			let key = key.as_bytes();
+			let shared = if i % RESTART_INTERVAL == 0 {
+				column.restarts.push(column.data.len() as u64);
+				0
+			} else {
+				prev.iter().zip(key).take_while(|(a, b)| a == b).count()
+			};
+			write_varint(&mut column.data, shared as u64);
+			write_varint(&mut column.data, (key.len() - shared) as u64);
+			column.data.extend_from_slice(&key[shared..]);
+			prev = key;
+		}
+		return column;
+	}
+
+	pub fn len(&self) -> usize {
+		self.count as usize
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.count == 0
+	}
+
+	/// Bytes taken in memory
+	pub fn size(&self) -> u64 {
+		return (self.data.len() + self.restarts.len() * std::mem::size_of::<u64>()) as u64;
+	}
+
+	/// Decodes only the chunk with the key
+	pub fn get(&self, id: usize) -> String {
+		assert!(id < self.len(), "key id out of bounds");
+		let mut pos = self.restarts[id / RESTART_INTERVAL] as usize;
+		let mut key = Vec::default();
+		for _ in 0..=id % RESTART_INTERVAL {
+			pos = self.decode_next(pos, &mut key);
+		}
+		return String::from_utf8_lossy(&key).into_owned();
+	}
+
+	pub fn decode_all(&self) -> Vec<String> {
+		let mut keys = Vec::with_capacity(self.len());
+		let mut pos = 0;
+		let mut key = Vec::default();
+		for _ in 0..self.len() {
+			pos = self.decode_next(pos, &mut key);
+			keys.push(String::from_utf8_lossy(&key).into_owned());
+		}
+		return keys;
+	}
+
+	/// Like `decode_all`, but checks the column read from disk,
+	/// so `get` and `decode_all` can't panic after it succeeds
+	pub fn try_decode_all(&self) -> Result<Vec<String>, anyhow::Error> {
+		let chunks = self.len().div_ceil(RESTART_INTERVAL);
+		if self.restarts.len() != chunks {
+			return Err(anyhow::anyhow!(
+				"{} key restarts for {} keys",
+				self.restarts.len(),
+				self.len()
+			));
+		}
+		let mut keys = Vec::with_capacity(self.len().min(self.data.len()));
+		let mut pos = 0;
+		let mut key = Vec::default();
+		for i in 0..self.len() {
+			let broken = |what: &str| anyhow::anyhow!("key {} at {}: {}", i, pos, what);
+			let (shared, next) =
+				try_read_varint(&self.data, pos).ok_or_else(|| broken("bad prefix"))?;
+			let (len, next) =
+				try_read_varint(&self.data, next).ok_or_else(|| broken("bad length"))?;
+			if i % RESTART_INTERVAL == 0 {
+				if self.restarts[i / RESTART_INTERVAL] != pos as u64 {
+					return Err(broken("restart points elsewhere"));
+				}
+				if shared != 0 {
+					return Err(broken("restart shares the prefix"));
+				}
+			}
+			if shared > key.len() as u64 {
+				return Err(broken("prefix is longer than the previous key"));
+			}
+			let end = next
+				.checked_add(len as usize)
+				.filter(|end| *end <= self.data.len())
+				.ok_or_else(|| broken("key is past the end of the column"))?;
+			key.truncate(shared as usize);
+			key.extend_from_slice(&self.data[next..end]);
+			keys.push(String::from_utf8_lossy(&key).into_owned());
+			pos = end;
+		}
+		if pos != self.data.len() {
+			return Err(anyhow::anyhow!(
+				"{} bytes after the last key",
+				self.data.len() - pos
+			));
+		}
+		return Ok(keys);
+	}
+
+	// replaces the previous key with the one at pos, returns the position of the next one
+	fn decode_next(&self, pos: usize, key: &mut Vec<u8>) -> usize {
+		let (shared, pos) = read_varint(&self.data, pos);
+		let (len, pos) = read_varint(&self.data, pos);
+		let end = pos + len as usize;
+		key.truncate(shared as usize);
+		key.extend_from_slice(&self.data[pos..end]);
+		return end;
+	}
+}
+
+/// Non decreasing timestamps stored as varint deltas.
+/// Timestamps are split into chunks, and the first timestamp of every chunk is kept
+/// in the sparse index, so seeks inside the column decode only one chunk.
+#[derive(Deserialize, Serialize, Debug, Default, Clone, PartialEq)]
+pub struct TimestampColumn {
+	count: u64,
+	// first timestamp of every chunk, it's not stored in data
+	firsts: Vec<Timestamp>,
+	// offset of every chunk in data
+	offsets: Vec<u64>,
+	#[serde(with = "serde_bytes")]
+	data: Vec<u8>,
+}
+
+#[allow(dead_code)]
+impl TimestampColumn {
+	pub fn encode(timestamps: &[Timestamp]) -> TimestampColumn {
+		let chunks = timestamps.len().div_ceil(TIMESTAMP_CHUNK);
+		let mut column = TimestampColumn {
+			count: timestamps.len() as u64,
+			firsts: Vec::with_capacity(chunks),
+			offsets: Vec::with_capacity(chunks),
+			data: Vec::default(),
+		};
+		for chunk in timestamps.chunks(TIMESTAMP_CHUNK) {
+			column.firsts.push(chunk[0]);
+			column.offsets.push(column.data.len() as u64);
+			for pair in chunk.windows(2) {
+				debug_assert!(pair[0] <= pair[1], "timestamps must be sorted");
+				write_varint(&mut column.data, pair[1] - pair[0]);
+			}
+		}
+		return column;
+	}
+
+	pub fn len(&self) -> usize {
+		self.count as usize
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.count == 0
+	}
+
+	/// Bytes taken in memory
+	pub fn size(&self) -> u64 {
+		let sparse = (self.firsts.len() + self.offsets.len()) * std::mem::size_of::<u64>();
+		return (self.data.len() + sparse) as u64;
+	}
+
+	pub fn get(&self, id: usize) -> Timestamp {
+		assert!(id < self.len(), "timestamp id out of bounds");
+		return self
+			.chunk(id / TIMESTAMP_CHUNK)
+			.nth(id % TIMESTAMP_CHUNK)
+			.unwrap();
+	}
+
+	pub fn decode_all(&self) -> Vec<Timestamp> {
+		(0..self.firsts.len())
+			.flat_map(|chunk| self.chunk(chunk))
+			.collect()
+	}
+
+	/// Like `decode_all`, but checks the column read from disk,
+	/// so `get`, `rows` and `decode_all` can't panic after it succeeds
+	pub fn try_decode_all(&self) -> Result<Vec<Timestamp>, anyhow::Error> {
+		let chunks = self.len().div_ceil(TIMESTAMP_CHUNK);
+		if self.firsts.len() != chunks || self.offsets.len() != chunks {
+			return Err(anyhow::anyhow!(
+				"{} chunk timestamps and {} chunk offsets for {} timestamps",
+				self.firsts.len(),
+				self.offsets.len(),
+				self.len()
+			));
+		}
+		let mut timestamps = Vec::with_capacity(self.len().min(self.data.len() + chunks));
+		let mut pos = 0;
+		for chunk in 0..chunks {
+			if self.offsets[chunk] != pos as u64 {
+				return Err(anyhow::anyhow!(
+					"timestamp chunk {} starts at {} instead of {}",
+					chunk,
+					self.offsets[chunk],
+					pos
+				));
+			}
+			let mut cur = self.firsts[chunk];
+			timestamps.push(cur);
+			let len = std::cmp::min(TIMESTAMP_CHUNK, self.len() - chunk * TIMESTAMP_CHUNK);
+			for _ in 1..len {
+				let (delta, next) = try_read_varint(&self.data, pos).ok_or_else(|| {
+					anyhow::anyhow!("timestamp {} at {} is broken", timestamps.len(), pos)
+				})?;
+				cur = cur
+					.checked_add(delta)
+					.ok_or_else(|| anyhow::anyhow!("timestamp {} overflows", timestamps.len()))?;
+				timestamps.push(cur);
+				pos = next;
+			}
+		}
+		if pos != self.data.len() {
+			return Err(anyhow::anyhow!(
+				"{} bytes after the last timestamp",
+				self.data.len() - pos
+			));
+		}
+		return Ok(timestamps);
+	}
+
+	/// Ids of the timestamps inside the (inclusive) range
+	pub fn rows(&self, range: (Timestamp, Timestamp)) -> Range<usize> {
+		let start = self.partition_point(|ts| ts < range.0);
+		let end = self.partition_point(|ts| ts <= range.1);
+		return start..std::cmp::max(start, end);
+	}
+
+	// like slice::partition_point, the answer is either inside the last chunk,
+	// that starts with the matching timestamp, or at the start of the next one
+	fn partition_point(&self, pred: impl Fn(Timestamp) -> bool) -> usize {
+		let chunk = self.firsts.partition_point(|ts| pred(*ts));
+		if chunk == 0 {
+			return 0;
+		}
+		let skipped = self.chunk(chunk - 1).take_while(|ts| pred(*ts)).count();
+		return (chunk - 1) * TIMESTAMP_CHUNK + skipped;
+	}
+
+	fn chunk(&self, chunk: usize) -> impl Iterator<Item = Timestamp> + '_ {
+		let len = std::cmp::min(TIMESTAMP_CHUNK, self.len() - chunk * TIMESTAMP_CHUNK);
+		let mut pos = self.offsets[chunk] as usize;
+		let mut cur = self.firsts[chunk];
+		(0..len).map(move |i| {
+			if i > 0 {
+				let (delta, next) = read_varint(&self.data, pos);
+				pos = next;
+				cur += delta;
+			}
+			cur
+		})
+	}
+}
+
+/// Opaque payloads of the documents stored one after another.
+/// Empty payload means, that the document doesn't have one.
+#[derive(Deserialize, Serialize, Debug, Default, Clone, PartialEq)]
+pub struct PayloadColumn {
+	// end of every payload in data
+	ends: Vec<u64>,
+	#[serde(with = "serde_bytes")]
+	data: Vec<u8>,
+}
+
+impl PayloadColumn {
+	pub fn encode(payloads: &[Vec<u8>]) -> PayloadColumn {
+		let mut column = PayloadColumn {
+			ends: Vec::with_capacity(payloads.len()),
+			data: Vec::with_capacity(payloads.iter().map(|payload| payload.len()).sum()),
+		};
+		for payload in payloads {
+			column.data.extend_from_slice(payload);
+			column.ends.push(column.data.len() as u64);
+		}
+		return column;
+	}
+
+	pub fn len(&self) -> usize {
+		self.ends.len()
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.ends.is_empty()
+	}
+
+	/// Bytes taken in memory
+	pub fn size(&self) -> u64 {
+		return (self.data.len() + self.ends.len() * std::mem::size_of::<u64>()) as u64;
+	}
+
+	/// Empty for the documents without the payload, and for the blocks without the column
+	pub fn get(&self, id: usize) -> &[u8] {
+		let end = match self.ends.get(id) {
+			Some(end) => *end as usize,
+			None => return &[],
+		};
+		let start = if id == 0 {
+			0
+		} else {
+			self.ends[id - 1] as usize
+		};
+		return &self.data[start..end];
+	}
+
+	pub fn decode_all(&self) -> Vec<Vec<u8>> {
+		(0..self.len()).map(|id| self.get(id).to_vec()).collect()
+	}
+
+	/// Checks the column read from disk, so `get` and `decode_all` can't panic
+	/// after it succeeds
+	pub fn validate(&self) -> Result<(), anyhow::Error> {
+		if let Some(id) = (1..self.ends.len()).find(|id| self.ends[id - 1] > self.ends[*id]) {
+			return Err(anyhow::anyhow!(
+				"payload {} ends before the previous one",
+				id
+			));
+		}
+		let end = self.ends.last().cloned().unwrap_or(0);
+		if end != self.data.len() as u64 {
+			return Err(anyhow::anyhow!(
+				"payloads end at {}, the column has {} bytes",
+				end,
+				self.data.len()
+			));
+		}
+		return Ok(());
+	}
+}
+
+pub fn write_varint(output: &mut Vec<u8>, mut value: u64) {
+	while value >= 0x80 {
+		output.push((value as u8) | 0x80);
+		value >>= 7;
+	}
+	output.push(value as u8);
+}
+
+/// Like `read_varint`, none if the value is past the end of the input or doesn't fit u64
+pub fn try_read_varint(input: &[u8], mut pos: usize) -> Option<(u64, usize)> {
+	let mut value = 0;
+	let mut shift = 0;
+	loop {
+		let byte = *input.get(pos)?;
+		pos += 1;
+		// the last byte of u64 has only one bit
+		if shift == 63 && byte > 1 {
+			return None;
+		}
+		value |= ((byte & 0x7f) as u64) << shift;
+		if byte & 0x80 == 0 {
+			return Some((value, pos));
+		}
+		shift += 7;
+	}
+}
+
+/// Returns the value and the position after it.
+/// Input must be valid, like the one checked by `try_read_varint`
+pub fn read_varint(input: &[u8], mut pos: usize) -> (u64, usize) {
+	let mut value = 0;
+	let mut shift = 0;
+	loop {
+		let byte = input[pos];
+		pos += 1;
+		value |= ((byte & 0x7f) as u64) << shift;
+		if byte & 0x80 == 0 {
+			return (value, pos);
+		}
+		shift += 7;
+	}
+}
+
+#[cfg(test)]
+#[path = "tests/columns.rs"]
+mod columns_test;
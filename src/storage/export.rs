@@ -0,0 +1,77 @@
+use super::*;
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+use std::sync::{Arc, RwLock};
+
+/// Format of the exported documents
+#[derive(Debug, Copy, Clone, PartialEq, Default, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum ExportFormat {
+	/// One `{"key", "tags", "timestamp", "payload"}` object per line, the import reads it back
+	#[default]
+	Jsonl,
+	/// Concatenated msgpack maps with the same fields
+	Msgpack,
+}
+
+impl ExportFormat {
+	pub fn content_type(&self) -> &'static str {
+		return match self {
+			ExportFormat::Jsonl => "application/x-ndjson",
+			ExportFormat::Msgpack => "application/msgpack",
+		};
+	}
+}
+
+pub fn write_record(
+	out: &mut impl Write,
+	record: &Record,
+	format: ExportFormat,
+) -> Result<(), anyhow::Error> {
+	match format {
+		ExportFormat::Jsonl => {
+			serde_json::to_writer(&mut *out, record)?;
+			out.write_all(b"\n")?;
+		}
+		ExportFormat::Msgpack => {
+			record.serialize(&mut rmp_serde::Serializer::new(out).with_struct_map())?;
+		}
+	}
+	return Ok(());
+}
+
+/// Documents of the block with the sorted `ids`, in the time order.
+/// Every index is read, because tags of the document are spread over all of them.
+pub fn read_matches(
+	block: &Arc<RwLock<dyn SearchBlock>>,
+	ids: &[Index],
+) -> Result<Vec<Record>, anyhow::Error> {
+	if ids.is_empty() {
+		return Ok(Vec::default());
+	}
+	// file block can be unloaded by another reader, so tags are copied
+	let block_tags = read_columns(block, &[Column::Tags])?.get_tags().to_vec();
+	let all: Vec<usize> = (0..block_tags.len()).collect();
+	let mut tags = vec![Vec::default(); ids.len()];
+	for (index, tag) in read_indexes(Arc::clone(block), &all)?.zip(block_tags) {
+		for doc in intersect(ids, &index) {
+			let pos = ids.partition_point(|x| *x < doc);
+			tags[pos].push(tag.clone());
+		}
+	}
+	let block = read_columns(block, &[Column::Keys, Column::Timestamps, Column::Payloads])?;
+	return Ok(ids
+		.iter()
+		.zip(tags)
+		.map(|(id, tags)| Record {
+			key: block.get_key(*id as usize),
+			tags,
+			timestamp: block.get_timestamp(*id as usize),
+			payload: block.get_payload(*id as usize),
+		})
+		.collect());
+}
+
+#[cfg(test)]
+#[path = "tests/export.rs"]
+mod export_test;
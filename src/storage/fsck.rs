@@ -0,0 +1,130 @@
+use super::*;
+use std::collections::BTreeSet;
+use std::fs::File;
+use std::path::Path;
+
+// corrupt files are moved here, inside the data dir
+pub const QUARANTINE_DIR: &str = "quarantine";
+
+#[derive(Debug, Default, Clone)]
+pub struct FileCheck {
+	pub name: String,
+	// empty if the file is fine
+	pub problems: Vec<String>,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct FsckReport {
+	// live files in the time order
+	pub files: Vec<FileCheck>,
+	// live files, that aren't on disk
+	pub missing: Vec<String>,
+	// block files, that aren't live, the storage moves them to quarantine when it's opened
+	pub orphans: Vec<String>,
+}
+
+impl FsckReport {
+	pub fn corrupt(&self) -> impl Iterator<Item = &FileCheck> {
+		self.files.iter().filter(|file| !file.problems.is_empty())
+	}
+
+	pub fn is_ok(&self) -> bool {
+		return self.corrupt().next().is_none() && self.missing.is_empty();
+	}
+}
+
+// problems and the range of the block, if it could be read
+fn check_file(path: &Path) -> (Vec<String>, Option<(Timestamp, Timestamp)>) {
+	let result = (|| -> Result<_, anyhow::Error> {
+		let len = std::fs::metadata(path)?.len();
+		let mut block = open_file(path)?;
+		let problems = block.verify(len);
+		return Ok((problems, block.range()));
+	})();
+	return match result {
+		Ok((problems, range)) => (problems, Some(range)),
+		Err(err) => (vec![err.to_string()], None),
+	};
+}
+
+/// Checks every live block file in the data dir, nothing is changed
+pub fn check_dir(dir: &Path) -> Result<FsckReport, anyhow::Error> {
+	let on_disk: BTreeSet<String> = block_files(dir)?.into_iter().collect();
+	// without the manifest the storage takes all block files as live
+	let mut live = Manifest::read_files(dir)?.unwrap_or_else(|| on_disk.iter().cloned().collect());
+	// the same order, as the storage opens them
+	sort_names(&mut live);
+
+	let mut report = FsckReport {
+		orphans: on_disk
+			.iter()
+			.filter(|name| !live.contains(name))
+			.cloned()
+			.collect(),
+		..Default::default()
+	};
+	// files are live in the time order, so every file starts after the previous one
+	let mut prev: Option<(String, Timestamp)> = None;
+	for name in live {
+		if !on_disk.contains(&name) {
+			report.missing.push(name);
+			continue;
+		}
+		let (mut problems, range) = check_file(&dir.join(&name));
+		if let (true, Some(range)) = (problems.is_empty(), range) {
+			match BlockName::parse(&name) {
+				Some(parsed) if (parsed.from, parsed.to) != range => problems.push(format!(
+					"name range {}..{} doesn't match the block range {}..{}",
+					parsed.from, parsed.to, range.0, range.1
+				)),
+				Some(_) => {}
+				None => problems.push("unexpected file name".to_string()),
+			}
+			match &prev {
+				Some((prev_name, prev_to)) if range.0 < *prev_to => problems.push(format!(
+					"range {}..{} overlaps with {}, that ends at {}",
+					range.0, range.1, prev_name, prev_to
+				)),
+				_ => {}
+			}
+			if problems.is_empty() {
+				prev = Some((name.clone(), range.1));
+			}
+		}
+		report.files.push(FileCheck { name, problems });
+	}
+	return Ok(report);
+}
+
+/// Moves the corrupt files to the quarantine dir, then removes them together with
+/// the missing files from the manifest, so the storage can be opened without them.
+/// Returns the quarantined files.
+pub fn quarantine(dir: &Path, report: &FsckReport) -> Result<Vec<String>, anyhow::Error> {
+	let corrupt: Vec<String> = report.corrupt().map(|file| file.name.clone()).collect();
+	if corrupt.is_empty() && report.missing.is_empty() {
+		return Ok(corrupt);
+	}
+	let quarantine = dir.join(QUARANTINE_DIR);
+	std::fs::create_dir_all(&quarantine)?;
+	// moved before the manifest is changed, otherwise the file becomes an orphan,
+	// and it's moved to quarantine on the next open anyway
+	for name in corrupt.iter() {
+		log::warn!("moving {} to quarantine", name);
+		std::fs::rename(dir.join(name), quarantine.join(name))?;
+	}
+	File::open(&quarantine)?.sync_all()?;
+	File::open(dir)?.sync_all()?;
+
+	let edits = corrupt
+		.iter()
+		.chain(report.missing.iter())
+		.map(|name| Edit::Remove(name.clone()))
+		.collect();
+	// without the manifest all files are live, so it's created from the files left
+	open_manifest(dir)?.apply(edits)?;
+	return Ok(corrupt);
+}
+
+#[cfg(test)]
+#[path = "tests/fsck.rs"]
+mod fsck_test;
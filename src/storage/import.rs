@@ -0,0 +1,241 @@
+use super::*;
+use serde::Deserialize;
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// numbers the temporary files of the sorted runs
+static NEXT_RUN: AtomicU64 = AtomicU64::new(0);
+
+/// Format of the bulk import input
+#[derive(Debug, Copy, Clone, PartialEq, clap::ValueEnum)]
+pub enum Format {
+	/// One `{"key", "tags", "timestamp"}` object per line, with the optional `payload`
+	Jsonl,
+	/// `key,tags,timestamp` columns with the header, tags are separated by spaces.
+	/// The optional `payload` column has the payload as JSON
+	Csv,
+}
+
+impl Format {
+	/// Format chosen by the file extension
+	pub fn from_path(path: &Path) -> Option<Format> {
+		return match path.extension().and_then(|ext| ext.to_str()) {
+			Some("jsonl") | Some("ndjson") | Some("json") => Some(Format::Jsonl),
+			Some("csv") => Some(Format::Csv),
+			_ => None,
+		};
+	}
+}
+
+#[derive(Debug, Deserialize)]
+struct CsvRecord {
+	key: String,
+	tags: String,
+	timestamp: Timestamp,
+	#[serde(default)]
+	payload: String,
+}
+
+/// Documents from the input in the input order, they're read on demand
+pub fn read_import<'a>(
+	input: impl Read + 'a,
+	format: Format,
+) -> Box<dyn Iterator<Item = Result<Record, anyhow::Error>> + 'a> {
+	return match format {
+		Format::Jsonl => Box::new(read_jsonl(input)),
+		Format::Csv => Box::new(read_csv(input)),
+	};
+}
+
+fn read_jsonl(input: impl Read) -> impl Iterator<Item = Result<Record, anyhow::Error>> {
+	BufReader::new(input)
+		.lines()
+		.enumerate()
+		.filter_map(|(i, line)| {
+			let line = match line {
+				Ok(line) => line,
+				Err(err) => return Some(Err(err.into())),
+			};
+			if line.trim().is_empty() {
+				return None;
+			}
+			let record = serde_json::from_str(&line)
+				.map_err(|err| anyhow::anyhow!("line {}: {}", i + 1, err));
+			return Some(record);
+		})
+}
+
+fn read_csv(input: impl Read) -> impl Iterator<Item = Result<Record, anyhow::Error>> {
+	let reader = csv::ReaderBuilder::new()
+		.trim(csv::Trim::All)
+		.from_reader(input);
+	reader.into_deserialize().enumerate().map(|(i, record)| {
+		let record: CsvRecord = record?;
+		let payload = match record.payload.as_str() {
+			"" => None,
+			payload => Some(
+				serde_json::from_str(payload)
+					.map_err(|err| anyhow::anyhow!("record {} payload: {}", i + 1, err))?,
+			),
+		};
+		return Ok(Record {
+			key: record.key,
+			tags: record.tags.split_whitespace().map(str::to_string).collect(),
+			timestamp: record.timestamp,
+			payload,
+		});
+	})
+}
+
+// sorted part of the input, only the last one stays in memory
+enum Run {
+	Memory(std::vec::IntoIter<Record>),
+	File(BufReader<File>, PathBuf),
+}
+
+impl Run {
+	fn next(&mut self) -> Result<Option<Record>, anyhow::Error> {
+		return match self {
+			Run::Memory(records) => Ok(records.next()),
+			Run::File(reader, _) => {
+				if reader.fill_buf()?.is_empty() {
+					return Ok(None);
+				}
+				Ok(Some(rmp_serde::from_read(&mut *reader)?))
+			}
+		};
+	}
+}
+
+/// Documents sorted by time with bounded memory. The input is cut into runs of about
+/// `run_size` bytes, every run is sorted and spilled into a temporary file, and then
+/// the runs are merged, while they're read. Temporary files are removed on drop.
+pub struct SortedRecords {
+	runs: Vec<Run>,
+	// the next document of every run
+	heads: Vec<Option<Record>>,
+	// (timestamp, run) of the heads, so equal timestamps keep the input order
+	heap: BinaryHeap<Reverse<(Timestamp, usize)>>,
+	range: Option<(Timestamp, Timestamp)>,
+	total: u64,
+}
+
+impl SortedRecords {
+	/// Reads the whole input, spilling the sorted runs into `dir`
+	pub fn new(
+		records: impl IntoIterator<Item = Result<Record, anyhow::Error>>,
+		dir: &Path,
+		run_size: u64,
+	) -> Result<SortedRecords, anyhow::Error> {
+		let mut sorted = SortedRecords {
+			runs: Vec::default(),
+			heads: Vec::default(),
+			heap: BinaryHeap::default(),
+			range: None,
+			total: 0,
+		};
+		let mut run = Vec::default();
+		let mut size = 0;
+		for record in records {
+			let record = record?;
+			let ts = record.timestamp;
+			sorted.range = Some(match sorted.range {
+				Some((from, to)) => (from.min(ts), to.max(ts)),
+				None => (ts, ts),
+			});
+			sorted.total += 1;
+			size += record.size();
+			run.push(record);
+			if size >= run_size {
+				sorted.spill(dir, std::mem::take(&mut run))?;
+				size = 0;
+			}
+		}
+		run.sort_by_key(|record| record.timestamp);
+		sorted.runs.push(Run::Memory(run.into_iter()));
+
+		for i in 0..sorted.runs.len() {
+			sorted.heads.push(None);
+			sorted.advance(i)?;
+		}
+		return Ok(sorted);
+	}
+
+	/// Time range of all documents, none if there are no documents
+	pub fn range(&self) -> Option<(Timestamp, Timestamp)> {
+		return self.range;
+	}
+
+	/// Number of all documents
+	pub fn total(&self) -> u64 {
+		return self.total;
+	}
+
+	fn spill(&mut self, dir: &Path, mut run: Vec<Record>) -> Result<(), anyhow::Error> {
+		run.sort_by_key(|record| record.timestamp);
+		let name = format!(
+			"import-{}-{}.tmp",
+			std::process::id(),
+			NEXT_RUN.fetch_add(1, Ordering::Relaxed)
+		);
+		let path = dir.join(name);
+		let result = (|| -> Result<File, anyhow::Error> {
+			let mut out = BufWriter::new(File::create(&path)?);
+			for record in run.iter() {
+				write_record(&mut out, record, ExportFormat::Msgpack)?;
+			}
+			out.flush()?;
+			return Ok(File::open(&path)?);
+		})();
+		match result {
+			Ok(file) => self.runs.push(Run::File(BufReader::new(file), path)),
+			Err(err) => {
+				std::fs::remove_file(&path).ok();
+				return Err(err);
+			}
+		}
+		return Ok(());
+	}
+
+	// reads the next head of the run
+	fn advance(&mut self, run: usize) -> Result<(), anyhow::Error> {
+		if let Some(record) = self.runs[run].next()? {
+			self.heap.push(Reverse((record.timestamp, run)));
+			self.heads[run] = Some(record);
+		}
+		return Ok(());
+	}
+}
+
+impl Iterator for SortedRecords {
+	type Item = Result<Record, anyhow::Error>;
+
+	fn next(&mut self) -> Option<Self::Item> {
+		let Reverse((_, run)) = self.heap.pop()?;
+		let record = self.heads[run].take().unwrap();
+		if let Err(err) = self.advance(run) {
+			return Some(Err(err));
+		}
+		return Some(Ok(record));
+	}
+}
+
+impl Drop for SortedRecords {
+	fn drop(&mut self) {
+		for run in self.runs.iter() {
+			if let Run::File(_, path) = run {
+				if let Err(err) = std::fs::remove_file(path) {
+					log::warn!("can't remove {}: {}", path.display(), err);
+				}
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+#[path = "tests/import.rs"]
+mod import_test;
@@ -0,0 +1,200 @@
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+pub const MANIFEST_NAME: &str = "MANIFEST";
+// the log is rewritten, when it has this many records more than the live files
+const REWRITE_RECORDS: usize = 1024;
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub enum Edit {
+	// file names are relative to the data dir
+	Add(String),
+	Remove(String),
+}
+
+/// Append only log of the block file changes, the source of truth for the live files.
+/// Every record is stored as (length, crc32, edit), so the record torn by a crash
+/// is detected and dropped on the next open.
+#[derive(Debug)]
+pub struct Manifest {
+	dir: PathBuf,
+	file: File,
+	// live files in the order they were added
+	files: Vec<String>,
+	records: usize,
+	// the log can have a torn record at the end, so nothing is appended after it
+	failed: bool,
+}
+
+impl Manifest {
+	pub fn open(dir: &Path) -> Result<Manifest, anyhow::Error> {
+		let path = dir.join(MANIFEST_NAME);
+		let mut file = File::options()
+			.create(true)
+			.truncate(false)
+			.read(true)
+			.append(true)
+			.open(&path)?;
+		let mut data = Vec::default();
+		file.read_to_end(&mut data)?;
+
+		let mut manifest = Manifest {
+			dir: dir.to_path_buf(),
+			file,
+			files: Vec::default(),
+			records: 0,
+			failed: false,
+		};
+		let mut pos = 0;
+		while let Some((edit, next)) = read_record(&data, pos) {
+			apply_edit(&mut manifest.files, edit);
+			manifest.records += 1;
+			pos = next;
+		}
+		if pos < data.len() {
+			log::warn!(
+				"dropping {} bytes of the broken manifest tail",
+				data.len() - pos
+			);
+			manifest.file.set_len(pos as u64)?;
+			manifest.file.seek(SeekFrom::End(0))?;
+		}
+		return Ok(manifest);
+	}
+
+	/// Creates the manifest with the live files, replacing the existing one at once
+	pub fn create(dir: &Path, files: &[String]) -> Result<Manifest, anyhow::Error> {
+		let tmp_path = write_tmp(dir, files)?;
+		std::fs::rename(&tmp_path, dir.join(MANIFEST_NAME))?;
+		File::open(dir)?.sync_all()?;
+		return Manifest::open(dir);
+	}
+
+	pub fn files(&self) -> &[String] {
+		&self.files
+	}
+
+	/// Edits are synced before returning, so they survive a crash.
+	/// On error none of the edits are applied.
+	pub fn apply(&mut self, edits: Vec<Edit>) -> Result<(), anyhow::Error> {
+		if self.failed {
+			return Err(anyhow::anyhow!(
+				"manifest isn't writable after the failed write"
+			));
+		}
+		let mut data = Vec::default();
+		for edit in edits.iter() {
+			write_record(&mut data, edit)?;
+		}
+		let end = self.file.seek(SeekFrom::End(0))?;
+		let result = self
+			.file
+			.write_all(&data)
+			.and_then(|_| self.file.sync_data());
+		if let Err(err) = result {
+			// open stops at the torn record, so the edits after it would be lost
+			if let Err(err) = self.file.set_len(end).and_then(|_| self.file.sync_data()) {
+				log::error!("can't cut the manifest back to {} bytes: {}", end, err);
+				self.failed = true;
+			}
+			return Err(err.into());
+		}
+		self.records += edits.len();
+		for edit in edits {
+			apply_edit(&mut self.files, edit);
+		}
+
+		if self.records > self.files.len() + REWRITE_RECORDS {
+			// the edits are already durable, so the failed rewrite is tried again later
+			if let Err(err) = self.rewrite() {
+				log::error!("can't rewrite manifest: {}", err);
+			}
+		}
+		return Ok(());
+	}
+
+	/// Replaces the log with the live files only
+	pub fn rewrite(&mut self) -> Result<(), anyhow::Error> {
+		log::info!("rewriting manifest: {} records", self.records);
+		let path = self.dir.join(MANIFEST_NAME);
+		let tmp_path = write_tmp(&self.dir, &self.files)?;
+		std::fs::rename(&tmp_path, &path)?;
+		// the old file isn't in the dir anymore, so appending to it would lose the edits
+		let reopen = || -> Result<File, anyhow::Error> {
+			File::open(&self.dir)?.sync_all()?;
+			return Ok(File::options().append(true).read(true).open(&path)?);
+		};
+		match reopen() {
+			Ok(file) => self.file = file,
+			Err(err) => {
+				self.failed = true;
+				return Err(err);
+			}
+		}
+		self.records = self.files.len();
+		return Ok(());
+	}
+
+	/// Live files, read without changing the log, none if there is no manifest
+	pub fn read_files(dir: &Path) -> Result<Option<Vec<String>>, anyhow::Error> {
+		let data = match std::fs::read(dir.join(MANIFEST_NAME)) {
+			Ok(data) => data,
+			Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+			Err(err) => return Err(err.into()),
+		};
+		let mut files = Vec::default();
+		let mut pos = 0;
+		while let Some((edit, next)) = read_record(&data, pos) {
+			apply_edit(&mut files, edit);
+			pos = next;
+		}
+		return Ok(Some(files));
+	}
+}
+
+// synced temporary file with the live files, that is renamed over the manifest
+fn write_tmp(dir: &Path, files: &[String]) -> Result<PathBuf, anyhow::Error> {
+	let mut data = Vec::default();
+	for name in files.iter() {
+		write_record(&mut data, &Edit::Add(name.clone()))?;
+	}
+	let tmp_path = dir.join(MANIFEST_NAME).with_extension("tmp");
+	let mut file = File::create(&tmp_path)?;
+	file.write_all(&data)?;
+	file.sync_all()?;
+	return Ok(tmp_path);
+}
+
+fn apply_edit(files: &mut Vec<String>, edit: Edit) {
+	match edit {
+		Edit::Add(name) => files.push(name),
+		Edit::Remove(name) => files.retain(|x| *x != name),
+	}
+}
+
+fn write_record(output: &mut Vec<u8>, edit: &Edit) -> Result<(), anyhow::Error> {
+	let payload = rmp_serde::to_vec(edit)?;
+	output.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+	output.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+	output.extend_from_slice(&payload);
+	return Ok(());
+}
+
+// none if the record is incomplete or broken
+fn read_record(data: &[u8], pos: usize) -> Option<(Edit, usize)> {
+	let header = data.get(pos..pos + 8)?;
+	let len = u32::from_le_bytes(header[0..4].try_into().unwrap()) as usize;
+	let crc = u32::from_le_bytes(header[4..8].try_into().unwrap());
+	let payload = data.get(pos + 8..pos + 8 + len)?;
+	if crc32fast::hash(payload) != crc {
+		return None;
+	}
+	let edit = rmp_serde::from_slice(payload).ok()?;
+	return Some((edit, pos + 8 + len));
+}
+
+#[cfg(test)]
+#[path = "tests/manifest.rs"]
+mod manifest_test;
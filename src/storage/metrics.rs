@@ -0,0 +1,202 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+// upper bounds of the latency buckets in seconds, the last one is +Inf
+const BUCKETS: [f64; 14] = [
+	0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+#[derive(Debug, Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+	pub fn add(&self, value: u64) {
+		self.0.fetch_add(value, Ordering::Relaxed);
+	}
+
+	pub fn get(&self) -> u64 {
+		return self.0.load(Ordering::Relaxed);
+	}
+}
+
+#[derive(Debug, Default)]
+pub struct Histogram {
+	// not cumulative, they are summed up only in the output
+	buckets: [AtomicU64; BUCKETS.len() + 1],
+	sum_micros: AtomicU64,
+	count: AtomicU64,
+}
+
+impl Histogram {
+	pub fn observe(&self, duration: Duration) {
+		let seconds = duration.as_secs_f64();
+		let bucket = BUCKETS.partition_point(|bound| *bound < seconds);
+		self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+		self.sum_micros
+			.fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+		self.count.fetch_add(1, Ordering::Relaxed);
+	}
+
+	fn write(&self, out: &mut String, name: &str, labels: &str) {
+		let (bucket_labels, labels) = if labels.is_empty() {
+			(String::new(), String::new())
+		} else {
+			(format!("{},", labels), format!("{{{}}}", labels))
+		};
+		let mut total = 0;
+		for (i, bucket) in self.buckets.iter().enumerate() {
+			total += bucket.load(Ordering::Relaxed);
+			let bound = match BUCKETS.get(i) {
+				Some(bound) => bound.to_string(),
+				None => "+Inf".to_string(),
+			};
+			writeln!(
+				out,
+				"{}_bucket{{{}le=\"{}\"}} {}",
+				name, bucket_labels, bound, total
+			)
+			.unwrap();
+		}
+		let sum = self.sum_micros.load(Ordering::Relaxed) as f64 / 1e6;
+		writeln!(out, "{}_sum{} {}", name, labels, sum).unwrap();
+		let count = self.count.load(Ordering::Relaxed);
+		writeln!(out, "{}_count{} {}", name, labels, count).unwrap();
+	}
+}
+
+/// Counters, that are updated by the storage as things happen
+#[derive(Debug, Default)]
+pub struct Metrics {
+	pub documents: Counter,
+	pub compactions: Counter,
+	pub compacted_blocks: Counter,
+	pub compaction_duration: Histogram,
+	pub block_writes: Counter,
+	pub write_failures: Counter,
+	pub query_duration: Histogram,
+	pub list_tags_duration: Histogram,
+}
+
+/// Values, that are taken from the storage state at the moment of the scrape
+#[derive(Debug, Default)]
+pub struct Gauges {
+	pub active_size: u64,
+	pub sealed_blocks: usize,
+	pub in_memory_blocks: usize,
+	pub file_blocks: usize,
+	pub loaded_blocks: usize,
+	pub loaded_size: u64,
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+	writeln!(out, "# HELP {} {}", name, help).unwrap();
+	writeln!(out, "# TYPE {} {}", name, kind).unwrap();
+}
+
+fn sample(out: &mut String, name: &str, kind: &str, help: &str, value: impl std::fmt::Display) {
+	header(out, name, kind, help);
+	writeln!(out, "{} {}", name, value).unwrap();
+}
+
+impl Metrics {
+	/// Metrics in the prometheus text format
+	pub fn render(&self, gauges: &Gauges) -> String {
+		let mut out = String::new();
+		sample(
+			&mut out,
+			"tagged_documents_total",
+			"counter",
+			"Documents pushed to the storage.",
+			self.documents.get(),
+		);
+		sample(
+			&mut out,
+			"tagged_active_size_bytes",
+			"gauge",
+			"Size of the active block.",
+			gauges.active_size,
+		);
+
+		header(&mut out, "tagged_blocks", "gauge", "Blocks by the type.");
+		for (kind, count) in [
+			("sealed", gauges.sealed_blocks),
+			("in_memory", gauges.in_memory_blocks),
+			("file", gauges.file_blocks),
+		] {
+			writeln!(out, "tagged_blocks{{type=\"{}\"}} {}", kind, count).unwrap();
+		}
+
+		sample(
+			&mut out,
+			"tagged_compactions_total",
+			"counter",
+			"Compactions, that merged at least one block.",
+			self.compactions.get(),
+		);
+		sample(
+			&mut out,
+			"tagged_compacted_blocks_total",
+			"counter",
+			"Blocks merged away by the compactions.",
+			self.compacted_blocks.get(),
+		);
+		header(
+			&mut out,
+			"tagged_compaction_duration_seconds",
+			"histogram",
+			"Duration of the compactions.",
+		);
+		self.compaction_duration
+			.write(&mut out, "tagged_compaction_duration_seconds", "");
+
+		sample(
+			&mut out,
+			"tagged_block_writes_total",
+			"counter",
+			"Blocks written on disk.",
+			self.block_writes.get(),
+		);
+		sample(
+			&mut out,
+			"tagged_block_write_failures_total",
+			"counter",
+			"Failed block writes, the blocks are written again later.",
+			self.write_failures.get(),
+		);
+
+		sample(
+			&mut out,
+			"tagged_loaded_blocks",
+			"gauge",
+			"File blocks with loaded columns or indexes.",
+			gauges.loaded_blocks,
+		);
+		sample(
+			&mut out,
+			"tagged_loaded_index_bytes",
+			"gauge",
+			"Memory taken by the loaded columns and indexes of the file blocks.",
+			gauges.loaded_size,
+		);
+
+		header(
+			&mut out,
+			"tagged_request_duration_seconds",
+			"histogram",
+			"Duration of the storage reads.",
+		);
+		self.query_duration
+			.write(&mut out, "tagged_request_duration_seconds", "op=\"query\"");
+		self.list_tags_duration.write(
+			&mut out,
+			"tagged_request_duration_seconds",
+			"op=\"list_tags\"",
+		);
+		return out;
+	}
+}
+
+#[cfg(test)]
+#[path = "tests/metrics.rs"]
+mod metrics_test;
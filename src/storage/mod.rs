@@ -1,5 +1,25 @@
 pub mod block;
+pub mod bloom;
+pub mod columns;
+pub mod export;
+pub mod fsck;
+pub mod import;
+pub mod manifest;
+pub mod metrics;
+pub mod naming;
+pub mod query;
 pub mod storage;
+pub mod units;
 
 pub use block::*;
+pub use bloom::*;
+pub use columns::*;
+pub use export::*;
+pub use fsck::*;
+pub use import::*;
+pub use manifest::*;
+pub use metrics::*;
+pub use naming::*;
+pub use query::*;
 pub use storage::*;
+pub use units::*;
@@ -0,0 +1,57 @@
+use super::Timestamp;
+use std::fmt::{Display, Formatter};
+
+pub const BLOCK_EXTENSION: &str = "index";
+// blocks written straight from memory
+pub const FLUSH_LEVEL: u32 = 0;
+
+/// Name of the block file: level, time range and the sequence number, that is unique
+/// inside the data dir. Numbers have fixed width, that fits any value, so sorting names
+/// sorts blocks by level, then by time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct BlockName {
+	pub level: u32,
+	pub from: Timestamp,
+	pub to: Timestamp,
+	pub seq: u64,
+}
+
+impl BlockName {
+	pub fn parse(name: &str) -> Option<BlockName> {
+		let stem = name.strip_suffix(BLOCK_EXTENSION)?.strip_suffix('.')?;
+		let mut parts = stem.split('-');
+		let mut next = || parts.next()?.parse().ok();
+		let level: u64 = next()?;
+		let result = BlockName {
+			level: level.try_into().ok()?,
+			from: next()?,
+			to: next()?,
+			seq: next()?,
+		};
+		if parts.next().is_some() {
+			return None;
+		}
+		return Some(result);
+	}
+}
+
+impl Display for BlockName {
+	fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+		write!(
+			f,
+			"{:010}-{:020}-{:020}-{:020}.{}",
+			self.level, self.from, self.to, self.seq, BLOCK_EXTENSION
+		)
+	}
+}
+
+/// Sorts block file names in the order the storage opens them: by level, then by time.
+/// Names are compared parsed, because older files have the narrower level,
+/// and the names, that can't be parsed, go first.
+pub fn sort_names(names: &mut [String]) {
+	names.sort_by_cached_key(|name| (BlockName::parse(name), name.clone()));
+}
+
+#[cfg(test)]
+#[path = "tests/naming.rs"]
+mod naming_test;
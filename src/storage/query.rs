@@ -0,0 +1,158 @@
+use super::*;
+use serde::Serialize;
+use std::sync::{Arc, RwLock};
+
+/// Documents, that have all of the tags and were pushed inside the (inclusive) range.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Query {
+	pub tags: Vec<String>,
+	pub range: (Timestamp, Timestamp),
+	// matches have payloads, they are read only then
+	pub payload: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Match {
+	pub key: String,
+	pub timestamp: Timestamp,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub payload: Option<serde_json::Value>,
+}
+
+impl Query {
+	pub fn new(tags: Vec<String>, range: (Timestamp, Timestamp)) -> Query {
+		Query {
+			tags,
+			range,
+			payload: false,
+		}
+	}
+
+	pub fn with_payload(mut self, payload: bool) -> Query {
+		self.payload = payload;
+		return self;
+	}
+
+	/// Checks only the data from the header, so nothing has to be loaded.
+	/// False if the block definitely doesn't have any matching documents.
+	pub fn may_match(&self, block: &dyn SearchBlock) -> bool {
+		match block.get_range() {
+			Some(block_range) if range_intersect(block_range, self.range) => {}
+			_ => return false,
+		}
+		return self.tags.iter().all(|tag| block.may_contain(tag));
+	}
+
+	/// Ids of the query tags in the block, ordered by the index length,
+	/// so the intersection starts from the smallest one.
+	/// None if the block can't have any matching documents.
+	/// Tags must be loaded.
+	pub fn plan(&self, block: &dyn SearchBlock) -> Option<Vec<usize>> {
+		if !self.may_match(block) {
+			return None;
+		}
+
+		let tags = block.get_tags();
+		let mut plan = Vec::with_capacity(self.tags.len());
+		for tag in self.tags.iter() {
+			let id = tags.binary_search(tag).ok()?;
+			if block.get_index_len(id) == 0 {
+				return None;
+			}
+			plan.push(id);
+		}
+		plan.sort_by_key(|id| (block.get_index_len(*id), *id));
+		plan.dedup();
+		return Some(plan);
+	}
+
+	/// Sorted ids of the matching rows in the block.
+	/// Indexes are read one by one, so if the intersection becomes empty
+	/// the rest of them will not be loaded at all.
+	pub fn execute(
+		&self,
+		block: &Arc<RwLock<dyn SearchBlock>>,
+	) -> Result<Vec<Index>, anyhow::Error> {
+		let covered = {
+			let block = block.read().unwrap();
+			if !self.may_match(&*block) {
+				return Ok(Vec::default());
+			}
+			// timestamps are needed only if the block crosses the range bounds
+			let (from, to) = block.get_range().unwrap();
+			self.range.0 <= from && to <= self.range.1
+		};
+		let columns: &[Column] = if covered {
+			&[Column::Tags]
+		} else {
+			&[Column::Tags, Column::Timestamps]
+		};
+		let (plan, rows) = {
+			let block = read_columns(block, columns)?;
+			match self.plan(&*block) {
+				Some(plan) if covered => (plan, 0..block.get_count()),
+				Some(plan) => (plan, block.get_rows(self.range)),
+				None => return Ok(Vec::default()),
+			}
+		};
+		if rows.is_empty() {
+			return Ok(Vec::default());
+		}
+		let (start, end) = (rows.start as Index, rows.end as Index);
+
+		let mut result: Option<Vec<Index>> = None;
+		for id in plan {
+			let index = read_indexes(Arc::clone(block), &[id])?.next().unwrap();
+			let next = match result {
+				None => {
+					let from = index.partition_point(|x| *x < start);
+					let to = index.partition_point(|x| *x < end);
+					index[from..to].to_vec()
+				}
+				Some(result) => intersect(&result, &index),
+			};
+			if next.is_empty() {
+				return Ok(next);
+			}
+			result = Some(next);
+		}
+		return Ok(result.unwrap_or_else(|| (start..end).collect()));
+	}
+}
+
+/// Intersection of two sorted lists.
+/// If one of them is much smaller, binary search is used instead of linear merge.
+pub fn intersect(a: &[Index], b: &[Index]) -> Vec<Index> {
+	let (small, large) = if a.len() <= b.len() { (a, b) } else { (b, a) };
+	let mut result = Vec::with_capacity(small.len());
+
+	if small.len() * 16 < large.len() {
+		let mut large = large;
+		for x in small {
+			let pos = large.partition_point(|y| y < x);
+			if pos < large.len() && large[pos] == *x {
+				result.push(*x);
+			}
+			large = &large[pos..];
+		}
+		return result;
+	}
+
+	let (mut i, mut j) = (0, 0);
+	while i < small.len() && j < large.len() {
+		match small[i].cmp(&large[j]) {
+			std::cmp::Ordering::Less => i += 1,
+			std::cmp::Ordering::Greater => j += 1,
+			std::cmp::Ordering::Equal => {
+				result.push(small[i]);
+				i += 1;
+				j += 1;
+			}
+		}
+	}
+	return result;
+}
+
+#[cfg(test)]
+#[path = "tests/query.rs"]
+mod query_test;
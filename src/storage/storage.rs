@@ -1,109 +1,169 @@
 use super::*;
+use arc_swap::ArcSwap;
+use futures::stream::{FuturesUnordered, StreamExt};
 use futures::Future;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, VecDeque};
 use std::fs::File;
-use std::path::PathBuf;
-use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::time::{Duration, Instant};
 use tokio::sync::Notify;
+use tokio::task::JoinHandle;
 
-#[allow(dead_code)]
-const MIN_TIME: Timestamp = 0;
-#[allow(dead_code)]
-const MAX_TIME: Timestamp = std::u64::MAX;
+pub const MIN_TIME: Timestamp = 0;
+pub const MAX_TIME: Timestamp = u64::MAX;
+// the lock file of the data dir
+pub const LOCK_NAME: &str = "LOCK";
 
-#[allow(dead_code)]
 pub fn range_intersect(a: (u64, u64), b: (u64, u64)) -> bool {
 	debug_assert!(a.0 <= a.1);
 	debug_assert!(b.0 <= b.1);
-	a.0 <= b.1 && a.1 <= b.0
+	a.0 <= b.1 && b.0 <= a.1
 }
 
-#[derive(Debug, Default, Clone)]
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct Config {
-	data_dir: PathBuf,
-	max_active_size: u64,
-	max_block_size: u64,
+	pub(crate) data_dir: PathBuf,
+	// bytes of keys, tag strings, postings and timestamps in the active block, when it's sealed.
+	// sizes are read with binary units: `k`, `kb` and `kib` all mean 1024 bytes
+	#[serde(with = "bytes")]
+	pub(crate) max_active_size: u64,
+	// bytes in the in memory block, when it's written on disk
+	#[serde(with = "bytes")]
+	pub(crate) max_block_size: u64,
+	// age of the oldest document in the active block, when it's sealed below the size limit
+	#[serde(with = "duration")]
+	pub(crate) max_active_age: Duration,
+	// age of the oldest document in the in memory block, when it's written on disk
+	// below the size limit
+	#[serde(with = "duration")]
+	pub(crate) max_block_age: Duration,
+	// file blocks with loaded columns or indexes, least recently used are unloaded first
+	pub(crate) max_loaded_blocks: usize,
+	// full active blocks, waiting for the save worker, before writers have to wait
+	pub(crate) max_pending_blocks: usize,
+	// return Overloaded error instead of waiting for the save worker
+	pub(crate) reject_on_backpressure: bool,
+	// blocks, that are written on disk at the same time
+	pub(crate) save_workers: usize,
+	// active blocks, that are filled in parallel and sealed together.
+	// while documents are in the active blocks, they are ordered only inside the shard
+	pub(crate) active_shards: usize,
 }
 
-struct StorageLockedIter<'a, T> {
-	data: RwLockReadGuard<'a, Vec<Arc<RwLock<T>>>>,
-	cur: usize,
-}
-
-impl<'a, T> StorageLockedIter<'a, T> {
-	pub fn new(data: RwLockReadGuard<'a, Vec<Arc<RwLock<T>>>>) -> StorageLockedIter<'a, T> {
-		StorageLockedIter {
-			cur: data.len(),
-			data,
+impl Default for Config {
+	fn default() -> Self {
+		Config {
+			data_dir: PathBuf::from("./data"),
+			max_active_size: 4 * 1024 * 1024,
+			max_block_size: 256 * 1024 * 1024,
+			max_active_age: Duration::from_secs(60),
+			max_block_age: Duration::from_secs(10 * 60),
+			max_loaded_blocks: 64,
+			max_pending_blocks: 4,
+			reject_on_backpressure: false,
+			save_workers: 2,
+			active_shards: 1,
 		}
 	}
 }
 
-impl<'a, T: SearchBlock + 'static> std::iter::Iterator for StorageLockedIter<'a, T> {
-	type Item = Arc<RwLock<dyn SearchBlock>>;
+impl Config {
+	pub fn data_dir(&self) -> &Path {
+		return &self.data_dir;
+	}
 
-	fn next(&mut self) -> Option<Self::Item> {
-		if self.cur > 0 {
-			self.cur -= 1;
-			Some(Arc::<RwLock<T>>::clone(&self.data[self.cur]))
-		} else {
-			None
-		}
+	pub fn validate(&self) -> Result<(), anyhow::Error> {
+		let check = |ok: bool, message: &str| {
+			if ok {
+				Ok(())
+			} else {
+				Err(anyhow::anyhow!("invalid config: {}", message))
+			}
+		};
+		check(self.max_active_size > 0, "max_active_size must be positive")?;
+		check(
+			self.max_block_size > self.max_active_size,
+			"max_block_size must be bigger than max_active_size",
+		)?;
+		check(
+			!self.max_active_age.is_zero(),
+			"max_active_age must be positive",
+		)?;
+		check(
+			!self.max_block_age.is_zero(),
+			"max_block_age must be positive",
+		)?;
+		check(
+			self.max_loaded_blocks > 0,
+			"max_loaded_blocks must be positive",
+		)?;
+		check(
+			self.max_pending_blocks > 0,
+			"max_pending_blocks must be positive",
+		)?;
+		check(self.save_workers > 0, "save_workers must be positive")?;
+		check(self.active_shards > 0, "active_shards must be positive")?;
+		return Ok(());
 	}
 }
 
-enum StorageIterType<'a> {
-	Active,
-	InMemory(StorageLockedIter<'a, InMemoryBlock>),
-	File(StorageLockedIter<'a, BlockFile<File>>),
+/// Immutable version of the sealed, in memory and file block lists.
+/// Every change publishes a new version, so readers holding the old one never block
+/// the save worker and always see each block exactly once.
+#[derive(Debug, Default, Clone)]
+pub struct BlockList {
+	pub(crate) version: u64,
+	// full active blocks, that aren't processed by the save worker yet
+	pub(crate) sealed: Vec<Arc<RwLock<InMemoryBlock>>>,
+	pub(crate) in_memory: Vec<Arc<RwLock<InMemoryBlock>>>,
+	pub(crate) files: Vec<Arc<RwLock<BlockFile<File>>>>,
 }
 
-pub struct StorageIter<'a> {
-	storage: &'a Storage,
-	cur: StorageIterType<'a>,
+/// Iterates over all blocks from the newest to the oldest
+pub struct StorageIter {
+	active: Vec<ActiveView>,
+	blocks: Arc<BlockList>,
+	sealed: usize,
+	in_memory: usize,
+	files: usize,
 }
 
-impl<'a> StorageIter<'a> {
-	pub fn new(storage: &'a Storage) -> StorageIter<'a> {
+impl StorageIter {
+	pub fn new(active: Vec<ActiveView>, blocks: Arc<BlockList>) -> StorageIter {
 		StorageIter {
-			storage,
-			cur: StorageIterType::Active,
+			active,
+			sealed: blocks.sealed.len(),
+			in_memory: blocks.in_memory.len(),
+			files: blocks.files.len(),
+			blocks,
 		}
 	}
 }
 
-impl<'a> std::iter::Iterator for StorageIter<'a> {
+impl std::iter::Iterator for StorageIter {
 	type Item = Arc<RwLock<dyn SearchBlock>>;
 
 	fn next(&mut self) -> Option<Self::Item> {
-		match &mut self.cur {
-			StorageIterType::Active => {
-				let active_lock = self.storage.active_block.read().unwrap();
-				let val = active_lock.clone();
-
-				// first acquire the lock, then drop the other one
-				// so active doesn't become inmemory inbetween
-				let inmemory_lock = self.storage.compact_list.read().unwrap();
-				std::mem::drop(active_lock);
-				self.cur = StorageIterType::InMemory(StorageLockedIter::new(inmemory_lock));
-
-				// make the conversion only here, so we don't take active_lock for too long
-				let val = val.into_block();
-
-				return Some(Arc::new(RwLock::new(val)));
-			}
-			StorageIterType::InMemory(iter) => {
-				let v = iter.next();
-				if v.is_some() {
-					v
-				} else {
-					// here we unlock after taking the lock too
-					let lock = self.storage.block_files.read().unwrap();
-					self.cur = StorageIterType::File(StorageLockedIter::new(lock));
-					self.next()
-				}
-			}
-			StorageIterType::File(iter) => iter.next(),
+		if let Some(active) = self.active.pop() {
+			return Some(Arc::new(RwLock::new(active)));
+		}
+		if self.sealed > 0 {
+			self.sealed -= 1;
+			return Some(self.blocks.sealed[self.sealed].clone());
+		}
+		if self.in_memory > 0 {
+			self.in_memory -= 1;
+			return Some(self.blocks.in_memory[self.in_memory].clone());
+		}
+		if self.files > 0 {
+			self.files -= 1;
+			return Some(self.blocks.files[self.files].clone());
 		}
+		return None;
 	}
 }
 
@@ -111,7 +171,7 @@ impl<'a> std::iter::Iterator for StorageIter<'a> {
 pub fn read_indexes(
 	block: Arc<RwLock<dyn SearchBlock>>,
 	ids: &[usize],
-) -> Result<impl Iterator<Item = Arc<Vec<u64>>>, anyhow::Error> {
+) -> Result<impl Iterator<Item = Postings>, anyhow::Error> {
 	let mut res = vec![None; ids.len()];
 	let mut skipped = Vec::default();
 	{
@@ -139,29 +199,273 @@ pub fn read_indexes(
 	return Ok(res.into_iter().map(|x| x.unwrap()));
 }
 
-#[derive(Debug, Clone, PartialEq, PartialOrd)]
+/// Loads columns, that aren't loaded yet, and returns the block locked for reading
+pub fn read_columns<'a>(
+	block: &'a Arc<RwLock<dyn SearchBlock>>,
+	columns: &[Column],
+) -> Result<RwLockReadGuard<'a, dyn SearchBlock>, anyhow::Error> {
+	loop {
+		{
+			let guard = block.read().unwrap();
+			if columns.iter().all(|column| guard.is_loaded(*column)) {
+				return Ok(guard);
+			}
+		}
+		// someone can unload the columns before we take the read lock again, so loop
+		let mut guard = block.write().unwrap();
+		for column in columns.iter() {
+			guard.read_column(*column)?;
+		}
+	}
+}
+
+// readers of the old block list versions can still hold the block, then it's copied
+fn take_block(block: Arc<RwLock<InMemoryBlock>>) -> InMemoryBlock {
+	return Arc::try_unwrap(block)
+		.map(|block| block.into_inner().unwrap())
+		.unwrap_or_else(|block| InMemoryBlock::clone(&block.read().unwrap()));
+}
+
+fn is_already_exists(err: &anyhow::Error) -> bool {
+	err.downcast_ref::<std::io::Error>()
+		.map(|err| err.kind() == std::io::ErrorKind::AlreadyExists)
+		.unwrap_or(false)
+}
+
+pub(crate) fn file_name(path: &Path) -> String {
+	path.file_name().unwrap().to_string_lossy().into_owned()
+}
+
+fn remove_file(path: &Path) {
+	if let Err(err) = std::fs::remove_file(path) {
+		log::warn!("can't remove {}: {}", path.display(), err);
+	}
+}
+
+/// Names of the block files in the dir, in no particular order
+pub fn block_files(dir: &Path) -> Result<Vec<String>, anyhow::Error> {
+	let mut names = Vec::default();
+	for entry in std::fs::read_dir(dir)? {
+		let path = entry?.path();
+		if path.extension().and_then(|ext| ext.to_str()) == Some(BLOCK_EXTENSION) {
+			names.push(file_name(&path));
+		}
+	}
+	return Ok(names);
+}
+
+/// Takes the exclusive lock of the data dir, so only one process changes it.
+/// The lock is released, when the file is closed or unlocked
+pub fn lock_dir(dir: &Path) -> Result<File, anyhow::Error> {
+	let file = File::options()
+		.create(true)
+		.truncate(false)
+		.write(true)
+		.open(dir.join(LOCK_NAME))?;
+	match file.try_lock() {
+		Ok(()) => return Ok(file),
+		Err(std::fs::TryLockError::WouldBlock) => {
+			return Err(anyhow::anyhow!(
+				"data dir {} is used by another process",
+				dir.display()
+			))
+		}
+		Err(std::fs::TryLockError::Error(err)) => return Err(err.into()),
+	}
+}
+
+// data dirs from before the manifest have only the block files, so all of them are live
+pub(crate) fn open_manifest(dir: &Path) -> Result<Manifest, anyhow::Error> {
+	if dir.join(MANIFEST_NAME).try_exists()? {
+		return Manifest::open(dir);
+	}
+	let mut names = block_files(dir)?;
+	sort_names(&mut names);
+	if !names.is_empty() {
+		log::warn!(
+			"no manifest in {}, creating it with {} block files",
+			dir.display(),
+			names.len()
+		);
+	}
+	return Manifest::create(dir, &names);
+}
+
+// temporary files are left after the crash and are removed. block files, that aren't
+// in the manifest, are left after the crash too, but the broken manifest can lose
+// the live ones, so they're moved to quarantine
+fn clean_orphans(dir: &Path, live: &[String]) -> Result<(), anyhow::Error> {
+	let live: BTreeSet<&str> = live.iter().map(|name| name.as_str()).collect();
+	let quarantine = dir.join(QUARANTINE_DIR);
+	for entry in std::fs::read_dir(dir)? {
+		let path = entry?.path();
+		match path.extension().and_then(|ext| ext.to_str()) {
+			Some("tmp") => {
+				log::warn!("removing temporary {}", path.display());
+				remove_file(&path);
+			}
+			Some(BLOCK_EXTENSION) if !live.contains(file_name(&path).as_str()) => {
+				log::warn!(
+					"moving orphan {} to {}",
+					path.display(),
+					quarantine.display()
+				);
+				std::fs::create_dir_all(&quarantine)?;
+				std::fs::rename(&path, quarantine.join(file_name(&path)))?;
+			}
+			_ => {}
+		}
+	}
+	return Ok(());
+}
+
+/// Opens the block file for reading, only the header is loaded
+pub fn open_file(path: &Path) -> Result<BlockFile<File>, anyhow::Error> {
+	let open = || -> Result<BlockFile<File>, anyhow::Error> {
+		let mut file = File::open(path)?;
+		let header = BlockHeader::read_header(&mut file, 0)?;
+		return Ok(header.open(file));
+	};
+	return open().map_err(|err| anyhow::anyhow!("can't open {}: {}", path.display(), err));
+}
+
+fn open_files(
+	dir: &Path,
+	names: &[String],
+) -> Result<Vec<Arc<RwLock<BlockFile<File>>>>, anyhow::Error> {
+	let mut files = Vec::with_capacity(names.len());
+	for name in names {
+		files.push(Arc::new(RwLock::new(open_file(&dir.join(name))?)));
+	}
+	return Ok(files);
+}
+
+// views can still read the sealed block, then it's copied
+fn take_active(block: Arc<RwLock<ActiveBlock>>) -> ActiveBlock {
+	return Arc::try_unwrap(block)
+		.map(|block| block.into_inner().unwrap())
+		.unwrap_or_else(|block| ActiveBlock::clone(&block.read().unwrap()));
+}
+
+// when the active block is sealed
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Seal {
+	// it's full
+	Full,
+	// it's full or older than `max_active_age`
+	Expired,
+	// it isn't empty
+	Any,
+}
+
+#[derive(Debug)]
+enum WriteState {
+	Writing,
+	Written(Box<BlockFile<File>>, PathBuf),
+	Failed,
+}
+
+#[derive(Default)]
+struct LoadedBlocks(VecDeque<Arc<RwLock<dyn SearchBlock>>>);
+
+impl std::fmt::Debug for LoadedBlocks {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		write!(f, "LoadedBlocks({})", self.0.len())
+	}
+}
+
+/// Returned instead of waiting, when there are too many blocks waiting to be saved
+/// and `reject_on_backpressure` is set.
+#[derive(Debug)]
+pub struct Overloaded;
+
+impl std::fmt::Display for Overloaded {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		write!(f, "storage is overloaded")
+	}
+}
+
+impl std::error::Error for Overloaded {}
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+pub struct Totals {
+	pub blocks: usize,
+	pub documents: u64,
+	pub disk_size: u64,
+	pub loaded_indexes: u64,
+	pub memory: u64,
+}
+
+impl Totals {
+	fn add(&mut self, block: &BlockStats) {
+		self.blocks += 1;
+		self.documents += block.documents;
+		self.disk_size += block.disk_size;
+		self.loaded_indexes += block.loaded_indexes;
+		self.memory += block.memory;
+	}
+}
+
+/// Distribution of the data between the tiers, sealed blocks are counted as in memory ones
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+pub struct Stats {
+	// newest first, like in the storage iterator
+	pub blocks: Vec<BlockStats>,
+	pub active: Totals,
+	pub in_memory: Totals,
+	pub files: Totals,
+	pub total: Totals,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
 pub struct Document {
 	pub key: String,
 	pub tags: Vec<String>,
+	// opaque for the storage, it isn't indexed
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub payload: Option<serde_json::Value>,
 }
 
 #[derive(Debug)]
 pub struct Storage {
-	block_files: RwLock<Vec<Arc<RwLock<BlockFile<File>>>>>,
-	compact_list: RwLock<Vec<Arc<RwLock<InMemoryBlock>>>>,
-	active_block: RwLock<Box<ActiveBlock>>,
+	blocks: ArcSwap<BlockList>,
+	// serializes block list updates, readers don't take it
+	update_lock: Mutex<()>,
+	// pushes go to the shards round-robin, so writers don't wait for each other.
+	// outer lock is held by the writer for the whole push, inner one only while the block
+	// is changed, so views can read it, and can keep the old block after sealing
+	active_shards: Vec<RwLock<Arc<RwLock<ActiveBlock>>>>,
+	next_shard: AtomicUsize,
+	// total size of the shards, changed under the shard lock
+	active_size: AtomicU64,
+	// (seq, timestamp) of the last push
+	clock: Mutex<(u64, Timestamp)>,
+	// notified, when the new active block is installed
+	active_notify: Notify,
+	// notified, when the new version of the block lists is published
+	blocks_notify: Notify,
+	// in memory blocks starting before it are written regardless of the size and age,
+	// zero if no snapshot waits for them
+	flush_until: AtomicU64,
+	// frozen blocks from the front of the in memory list, in the same order
+	writing: Mutex<VecDeque<(Arc<RwLock<InMemoryBlock>>, WriteState)>>,
+	manifest: Mutex<Manifest>,
+	// held until the storage is stopped
+	lock: File,
+	loaded: Mutex<LoadedBlocks>,
+	metrics: Metrics,
 	config: Config,
-	context: Arc<uuid::v1::Context>,
+	// sequence number of the next block file
+	next_seq: AtomicU64,
 
 	bg_notify: Notify,
-	stopped: std::sync::atomic::AtomicBool,
+	stopped: AtomicBool,
 }
 
 #[allow(dead_code)]
 impl Storage {
 	pub fn new(
 		config: Config,
-		context: Arc<uuid::v1::Context>,
 	) -> Result<
 		(
 			Arc<Storage>,
@@ -169,14 +473,48 @@ impl Storage {
 		),
 		anyhow::Error,
 	> {
+		config.validate()?;
+		let lock = lock_dir(&config.data_dir)?;
+		let manifest = open_manifest(&config.data_dir)?;
+		clean_orphans(&config.data_dir, manifest.files())?;
+		// imported files are added to the manifest after the newer ones, so the files
+		// are opened in the time order of their names
+		let mut names = manifest.files().to_vec();
+		sort_names(&mut names);
+		let files = open_files(&config.data_dir, &names)?;
+		let next_seq = manifest
+			.files()
+			.iter()
+			.filter_map(|name| BlockName::parse(name))
+			.map(|name| name.seq + 1)
+			.max()
+			.unwrap_or(0);
+		log::info!("opened {} block files", files.len());
+
 		let storage = Arc::new(Storage {
-			block_files: Default::default(),
-			compact_list: Default::default(),
-			active_block: Default::default(),
+			blocks: ArcSwap::from_pointee(BlockList {
+				files,
+				..Default::default()
+			}),
+			manifest: Mutex::new(manifest),
+			lock,
+			update_lock: Default::default(),
+			active_shards: (0..config.active_shards)
+				.map(|_| Default::default())
+				.collect(),
+			next_shard: Default::default(),
+			active_size: Default::default(),
+			clock: Default::default(),
+			active_notify: Default::default(),
+			blocks_notify: Default::default(),
+			flush_until: Default::default(),
+			writing: Default::default(),
+			loaded: Default::default(),
+			metrics: Default::default(),
 			bg_notify: Default::default(),
 			stopped: Default::default(),
 			config,
-			context,
+			next_seq: AtomicU64::new(next_seq),
 		});
 
 		let self_copy = Arc::clone(&storage);
@@ -187,26 +525,36 @@ impl Storage {
 		});
 
 		let self_copy = Arc::clone(&storage);
-		let stop = async {
-			self_copy.send_stop();
-			join.await.map_err(anyhow::Error::msg)
+		let stop = async move {
+			Arc::clone(&self_copy).send_stop();
+			let result = join.await.map_err(anyhow::Error::msg);
+			// the dir can be opened again, even if the storage is still referenced
+			if let Err(err) = self_copy.lock.unlock() {
+				log::error!(
+					"can't unlock {}: {}",
+					self_copy.config.data_dir.display(),
+					err
+				);
+			}
+			result
 		};
 
 		return Ok((storage, stop));
 	}
 
 	pub async fn push(&self, key: String, tags: Vec<String>) -> Result<(), anyhow::Error> {
-		self.push_impl(|active| {
-			active.push(key, tags);
+		self.push_impl(1, |active, seq, ts| {
+			active.push_at(seq, ts, key, tags);
 		})
 		.await
 	}
 
 	// can overflow active block size up to batch size
 	pub async fn push_batch(&self, docs: Vec<Document>) -> Result<(), anyhow::Error> {
-		self.push_impl(|active| {
+		self.push_impl(docs.len(), |active, seq, ts| {
 			for doc in docs {
-				active.push(doc.key, doc.tags);
+				let payload = encode_payload(doc.payload.as_ref());
+				active.push_with_payload(seq, ts, doc.key, doc.tags, payload);
 			}
 		})
 		.await
@@ -214,87 +562,669 @@ impl Storage {
 
 	async fn push_impl(
 		&self,
-		pusher: impl FnOnce(&mut Box<ActiveBlock>),
+		docs: usize,
+		pusher: impl FnOnce(&mut ActiveBlock, u64, Timestamp),
 	) -> Result<(), anyhow::Error> {
-		let mut active = self.acquire_active().await;
-		pusher(&mut active);
-		if active.size() >= self.config.max_active_size {
+		let size = {
+			let shard = self.acquire_active().await?;
+			let (seq, ts) = self.next_stamp();
+			let mut active = shard.write().unwrap();
+			let start_size = active.size();
+			pusher(&mut active, seq, ts);
+			self.metrics.documents.add(docs as u64);
+			let added = active.size() - start_size;
+			self.active_size.fetch_add(added, Ordering::SeqCst) + added
+		};
+		if size >= self.config.max_active_size && self.seal_active(Seal::Full) {
+			self.active_notify.notify_waiters();
 			self.bg_notify.notify_one();
 		}
 		return Ok(());
 	}
 
-	async fn acquire_active<'a>(&'a self) -> RwLockWriteGuard<'a, Box<ActiveBlock>> {
-		// in some cases we can give no runtime for block saving,
-		// so we'll force ourselfes to yield here, if he hit the limit
+	async fn acquire_active(
+		&self,
+	) -> Result<RwLockWriteGuard<'_, Arc<RwLock<ActiveBlock>>>, anyhow::Error> {
 		loop {
+			// notified future receives notify_waiters right after creation,
+			// so we can't miss the notification between the check and await
+			let notified = self.active_notify.notified();
+			// concurrent writers can overflow the active size a bit, it's fine
+			if self.active_size.load(Ordering::SeqCst) < self.config.max_active_size {
+				let shard =
+					self.next_shard.fetch_add(1, Ordering::Relaxed) % self.active_shards.len();
+				return Ok(self.active_shards[shard].write().unwrap());
+			}
+			if self.stopped.load(Ordering::SeqCst) {
+				return Err(anyhow::anyhow!("storage is stopped"));
+			}
+			if self.config.reject_on_backpressure {
+				return Err(Overloaded.into());
+			}
+			notified.await;
+		}
+	}
+
+	// must be called under the shard lock, so every shard gets pushes in the seq order
+	fn next_stamp(&self) -> (u64, Timestamp) {
+		let now = current_time();
+		let mut clock = self.clock.lock().unwrap();
+		// timestamps never go back, even between the blocks
+		*clock = (clock.0 + 1, std::cmp::max(clock.1, now));
+		return *clock;
+	}
+
+	// moves the active block to the sealed list, if there is a place for it
+	fn seal_active(&self, seal: Seal) -> bool {
+		// hold all shard locks, so readers see the block either as active or as sealed
+		let mut shards: Vec<_> = self
+			.active_shards
+			.iter()
+			.map(|shard| shard.write().unwrap())
+			.collect();
+		let size: u64 = shards
+			.iter()
+			.map(|shard| shard.read().unwrap().size())
+			.sum();
+		let expired = seal == Seal::Expired
+			&& shards
+				.iter()
+				.filter_map(|shard| shard.read().unwrap().first_timestamp())
+				.min()
+				.map(|first| self.is_expired(first, self.config.max_active_age))
+				.unwrap_or(false);
+		let sealed = match seal {
+			Seal::Any => size > 0,
+			_ => size >= self.config.max_active_size || expired,
+		};
+		if !sealed {
+			// someone has already sealed it
+			return false;
+		}
+		return self.update_blocks(|blocks| {
+			if blocks.sealed.len() >= self.config.max_pending_blocks {
+				return false;
+			}
+			log::info!("sealing active block");
+			let shards = shards
+				.iter_mut()
+				.map(|shard| take_active(std::mem::take(&mut **shard)));
+			let block = ActiveBlock::merge(shards.collect()).into_block();
+			blocks.sealed.push(Arc::new(RwLock::new(block)));
+			self.active_size.fetch_sub(size, Ordering::SeqCst);
+			return true;
+		});
+	}
+
+	/// Current version of the block lists, it stays valid while the storage changes
+	pub fn block_list(&self) -> Arc<BlockList> {
+		return self.blocks.load_full();
+	}
+
+	// publishes the new version of the block lists, if `update` returns true
+	fn update_blocks(&self, update: impl FnOnce(&mut BlockList) -> bool) -> bool {
+		let _guard = self.update_lock.lock().unwrap();
+		let mut blocks = BlockList::clone(&self.blocks.load());
+		if !update(&mut blocks) {
+			return false;
+		}
+		blocks.version += 1;
+		self.blocks.store(Arc::new(blocks));
+		self.blocks_notify.notify_waiters();
+		return true;
+	}
+
+	pub fn iter(&self) -> StorageIter {
+		let (active, blocks) = self.views();
+		return StorageIter::new(active, blocks);
+	}
+
+	// views of the active shards and the block lists at the same moment
+	fn views(&self) -> (Vec<ActiveView>, Arc<BlockList>) {
+		let shards: Vec<_> = self
+			.active_shards
+			.iter()
+			.map(|shard| shard.read().unwrap())
+			.collect();
+		// take the block lists under the active locks, so the sealed block can't be missed
+		let blocks = self.block_list();
+		let active = shards
+			.iter()
+			.map(|shard| ActiveView::new(Arc::clone(shard)))
+			.collect();
+		return (active, blocks);
+	}
+
+	// matching rows of the active shards as (seq, id, shard), in the push order
+	fn active_matches(
+		query: &Query,
+		active: &[Arc<RwLock<ActiveView>>],
+	) -> Result<Vec<(u64, Index, usize)>, anyhow::Error> {
+		let mut result = Vec::default();
+		for (shard, view) in active.iter().enumerate() {
+			let block: Arc<RwLock<dyn SearchBlock>> = view.clone();
+			let ids = query.execute(&block)?;
+			let view = view.read().unwrap();
+			result.extend(
+				ids.into_iter()
+					.map(|id| (view.get_seq(id as usize), id, shard)),
+			);
+		}
+		result.sort_unstable();
+		return Ok(result);
+	}
+
+	pub fn config(&self) -> &Config {
+		return &self.config;
+	}
+
+	/// Sorted and deduplicated tags starting with `prefix` from all blocks, that intersect `range`.
+	/// Tags are returned in pages of `limit` size, `after` is the last tag of the previous page.
+	pub fn list_tags(
+		&self,
+		prefix: &str,
+		after: Option<&str>,
+		range: (Timestamp, Timestamp),
+		limit: usize,
+	) -> Result<Vec<String>, anyhow::Error> {
+		let start = Instant::now();
+		let mut result: BTreeSet<String> = BTreeSet::default();
+		for block_lock in self.iter() {
+			match block_lock.read().unwrap().get_range() {
+				Some(block_range) if range_intersect(block_range, range) => {}
+				_ => continue,
+			}
+
+			let block = read_columns(&block_lock, &[Column::Tags])?;
+			let tags = block.get_tags();
+			let start = match after {
+				Some(after) if after >= prefix => tags.partition_point(|tag| tag.as_str() <= after),
+				_ => tags.partition_point(|tag| tag.as_str() < prefix),
+			};
+			// every block can give us at most limit tags, and if the page is already full
+			// there is no need to look at the tags after the last one
+			let last = if result.len() < limit {
+				None
+			} else {
+				result.last().cloned()
+			};
+			for tag in tags[start..]
+				.iter()
+				.take_while(|tag| tag.starts_with(prefix))
+				.take_while(|tag| last.as_ref().map(|last| *tag < last).unwrap_or(true))
+				.take(limit)
 			{
-				let active = self.active_block.write().unwrap();
-				if active.size() < self.config.max_active_size {
-					return active;
+				result.insert(tag.clone());
+			}
+			while result.len() > limit {
+				result.pop_last();
+			}
+			std::mem::drop(block);
+			self.track_loaded(&block_lock);
+		}
+		self.metrics.list_tags_duration.observe(start.elapsed());
+		return Ok(result.into_iter().collect());
+	}
+
+	/// Documents matching the query, newest first.
+	pub fn query(&self, query: &Query, limit: usize) -> Result<Vec<Match>, anyhow::Error> {
+		let start = Instant::now();
+		let mut columns = vec![Column::Keys, Column::Timestamps];
+		if query.payload {
+			columns.push(Column::Payloads);
+		}
+		let get_match = |block: &dyn SearchBlock, id: usize| Match {
+			key: block.get_key(id),
+			timestamp: block.get_timestamp(id),
+			payload: query.payload.then(|| block.get_payload(id)).flatten(),
+		};
+
+		// active shards overlap in time, so their matches are merged before the limit
+		let (active, blocks) = self.views();
+		let active: Vec<_> = active
+			.into_iter()
+			.map(|view| Arc::new(RwLock::new(view)))
+			.collect();
+		let mut result: Vec<_> = Self::active_matches(query, &active)?
+			.into_iter()
+			.rev()
+			.take(limit)
+			.map(|(_, id, shard)| get_match(&*active[shard].read().unwrap(), id as usize))
+			.collect();
+
+		for block in StorageIter::new(Vec::default(), blocks) {
+			if result.len() >= limit {
+				break;
+			}
+			let ids = query.execute(&block)?;
+			if !ids.is_empty() {
+				let block = read_columns(&block, &columns)?;
+				for id in ids.into_iter().rev().take(limit - result.len()) {
+					result.push(get_match(&*block, id as usize));
 				}
 			}
-			tokio::task::yield_now().await;
+			self.track_loaded(&block);
 		}
+		self.metrics.query_duration.observe(start.elapsed());
+		return Ok(result);
 	}
 
-	pub fn iter<'a>(&'a self) -> StorageIter<'a> {
-		return StorageIter::new(self);
+	/// Makes a point in time copy of the storage in `dest`, that can be opened as a data dir.
+	/// Active and in memory blocks are written first, then the block files are linked,
+	/// or copied, if they are on another file system. Pushes continue meanwhile.
+	/// Returns the number of the copied files.
+	pub async fn snapshot(self: &Arc<Self>, dest: &Path) -> Result<usize, anyhow::Error> {
+		std::fs::create_dir_all(dest)?;
+		if std::fs::read_dir(dest)?.next().is_some() {
+			return Err(anyhow::anyhow!("{} isn't empty", dest.display()));
+		}
+
+		loop {
+			// notified future receives notify_waiters right after creation,
+			// so we can't miss the notification between the check and await
+			let notified = self.blocks_notify.notified();
+			if self.active_size.load(Ordering::SeqCst) == 0 || self.seal_active(Seal::Any) {
+				break;
+			}
+			if self.stopped.load(Ordering::SeqCst) {
+				return Err(anyhow::anyhow!("storage is stopped"));
+			}
+			// the sealed list is full, wait for the save worker
+			self.bg_notify.notify_one();
+			notified.await;
+		}
+		self.active_notify.notify_waiters();
+
+		let blocks = self.block_list();
+		let last = blocks
+			.sealed
+			.last()
+			.or_else(|| blocks.in_memory.last())
+			.map(|block| block.read().unwrap().range().1);
+		if let Some(last) = last {
+			let flush = last + 1;
+			self.flush_until.fetch_max(flush, Ordering::SeqCst);
+			loop {
+				let notified = self.blocks_notify.notified();
+				let blocks = self.block_list();
+				let flushed = blocks
+					.sealed
+					.iter()
+					.chain(blocks.in_memory.iter())
+					.all(|block| block.read().unwrap().range().0 >= flush);
+				if flushed {
+					break;
+				}
+				if self.stopped.load(Ordering::SeqCst) {
+					return Err(anyhow::anyhow!("storage is stopped"));
+				}
+				self.bg_notify.notify_one();
+				notified.await;
+			}
+			// another snapshot could wait for the newer blocks
+			self.flush_until
+				.compare_exchange(flush, 0, Ordering::SeqCst, Ordering::SeqCst)
+				.ok();
+		}
+
+		let self_copy = Arc::clone(self);
+		let dest = dest.to_path_buf();
+		return tokio::task::spawn_blocking(move || self_copy.copy_files(&dest)).await?;
+	}
+
+	fn copy_files(&self, dest: &Path) -> Result<usize, anyhow::Error> {
+		// files are never removed from the running storage, so the live ones stay on disk
+		let names = self.manifest.lock().unwrap().files().to_vec();
+		for name in names.iter() {
+			let (from, to) = (self.config.data_dir.join(name), dest.join(name));
+			if let Err(err) = std::fs::hard_link(&from, &to) {
+				log::debug!("can't link {}, copying: {}", from.display(), err);
+				std::fs::copy(&from, &to)?;
+				File::open(&to)?.sync_all()?;
+			}
+		}
+		File::open(dest)?.sync_all()?;
+		// manifest is the last, so the snapshot isn't valid until all files are there
+		let edits = names.iter().map(|name| Edit::Add(name.clone())).collect();
+		Manifest::open(dest)?.apply(edits)?;
+		File::open(dest)?.sync_all()?;
+		log::info!("snapshot {}: {} files", dest.display(), names.len());
+		return Ok(names.len());
+	}
+
+	/// Passes every document matching the query to `output`, oldest first.
+	/// Returns the number of the exported documents.
+	pub fn export(
+		&self,
+		query: &Query,
+		mut output: impl FnMut(Record) -> Result<(), anyhow::Error>,
+	) -> Result<u64, anyhow::Error> {
+		let (active, blocks) = self.views();
+		let mut blocks: Vec<_> = StorageIter::new(Vec::default(), blocks).collect();
+		blocks.reverse();
+		let mut count = 0;
+		for block in blocks {
+			let ids = query.execute(&block)?;
+			let records = read_matches(&block, &ids)?;
+			self.track_loaded(&block);
+			for record in records {
+				output(record)?;
+				count += 1;
+			}
+		}
+
+		// active shards overlap in time, so their documents are merged in the push order
+		let active: Vec<_> = active
+			.into_iter()
+			.map(|view| Arc::new(RwLock::new(view)))
+			.collect();
+		let matches = Self::active_matches(query, &active)?;
+		let mut records = Vec::with_capacity(active.len());
+		for (shard, view) in active.iter().enumerate() {
+			let ids: Vec<_> = matches
+				.iter()
+				.filter(|(_, _, x)| *x == shard)
+				.map(|(_, id, _)| *id)
+				.collect();
+			let block: Arc<RwLock<dyn SearchBlock>> = view.clone();
+			records.push(read_matches(&block, &ids)?.into_iter());
+		}
+		for (_, _, shard) in matches {
+			output(records[shard].next().unwrap())?;
+			count += 1;
+		}
+		return Ok(count);
+	}
+
+	pub fn stats(&self) -> Stats {
+		let mut stats = Stats::default();
+		for block in self.iter() {
+			let block = block.read().unwrap().get_stats();
+			let totals = match block.block_type {
+				BlockType::Active => &mut stats.active,
+				BlockType::InMemory => &mut stats.in_memory,
+				BlockType::File => &mut stats.files,
+			};
+			totals.add(&block);
+			stats.total.add(&block);
+			stats.blocks.push(block);
+		}
+		return stats;
+	}
+
+	/// Metrics in the prometheus text format
+	pub fn metrics(&self) -> String {
+		let blocks = self.block_list();
+		let loaded = self.loaded.lock().unwrap().0.clone();
+		let gauges = Gauges {
+			active_size: self.active_size.load(Ordering::SeqCst),
+			sealed_blocks: blocks.sealed.len(),
+			in_memory_blocks: blocks.in_memory.len(),
+			file_blocks: blocks.files.len(),
+			loaded_blocks: loaded.len(),
+			loaded_size: loaded
+				.iter()
+				.map(|block| block.read().unwrap().get_stats().memory)
+				.sum(),
+		};
+		return self.metrics.render(&gauges);
+	}
+
+	// marks file block as recently used, and unloads the least recently used ones
+	// if there are too many loaded blocks
+	fn track_loaded(&self, block: &Arc<RwLock<dyn SearchBlock>>) {
+		{
+			let block = block.read().unwrap();
+			if !matches!(block.get_type(), BlockType::File) || !block.is_loaded(Column::Tags) {
+				return;
+			}
+		}
+		let mut loaded = self.loaded.lock().unwrap();
+		loaded.0.retain(|x| !Arc::ptr_eq(x, block));
+		loaded.0.push_back(Arc::clone(block));
+		while loaded.0.len() > self.config.max_loaded_blocks {
+			let block = loaded.0.pop_front().unwrap();
+			block.write().unwrap().unload();
+		}
 	}
 
 	pub fn send_stop(self: Arc<Self>) {
-		self.stopped
-			.store(true, std::sync::atomic::Ordering::SeqCst);
+		self.stopped.store(true, Ordering::SeqCst);
 		self.bg_notify.notify_waiters();
+		self.active_notify.notify_waiters();
+		self.blocks_notify.notify_waiters();
 	}
 
-	async fn save_worker(self: &Arc<Self>) {
-		while !self.stopped.load(std::sync::atomic::Ordering::SeqCst) {
-			self.bg_notify.notified().await;
+	fn is_expired(&self, first: Timestamp, max_age: Duration) -> bool {
+		return current_time().saturating_sub(first) >= max_age.as_millis() as Timestamp;
+	}
 
-			let self_copy = Arc::clone(self);
-			tokio::task::spawn_blocking(move || {
-				self_copy.save_active();
-			})
-			.await
-			.unwrap();
+	/// Writes the documents straight into block files, bypassing the active block, and
+	/// adds them in front of the file blocks. Documents can come in any order, they're
+	/// sorted in runs of `max_block_size`, that are spilled into temporary files in the data
+	/// dir. Imported documents must not be newer than the oldest document in the storage,
+	/// because the blocks must stay ordered by time, otherwise nothing is imported.
+	/// Returns the number of written files.
+	pub fn import(
+		&self,
+		records: impl IntoIterator<Item = Result<Record, anyhow::Error>>,
+	) -> Result<usize, anyhow::Error> {
+		let records =
+			SortedRecords::new(records, &self.config.data_dir, self.config.max_block_size)?;
+		let range = match records.range() {
+			Some(range) => range,
+			None => return Ok(0),
+		};
+		// pushes stamped before the clock is read are in the active blocks, when they're
+		// read, so the active blocks are checked only once, outside of the update lock.
+		// later pushes get timestamps not before `floor`
+		let (seq, floor) = {
+			let clock = self.clock.lock().unwrap();
+			(clock.0, std::cmp::max(clock.1, current_time()))
+		};
+		let oldest_active = self
+			.active_shards
+			.iter()
+			.filter_map(|shard| shard.read().unwrap().read().unwrap().first_timestamp())
+			.min();
+		let check = |blocks: &BlockList| -> Result<(), anyhow::Error> {
+			let oldest = blocks
+				.files
+				.first()
+				.map(|block| block.read().unwrap().range().0)
+				.or_else(|| {
+					blocks
+						.in_memory
+						.first()
+						.map(|b| b.read().unwrap().range().0)
+				})
+				.or_else(|| blocks.sealed.first().map(|b| b.read().unwrap().range().0))
+				.or(oldest_active);
+			match oldest {
+				Some(oldest) if oldest < range.1 => Err(anyhow::anyhow!(
+					"imported documents {}..{} overlap with the storage, that starts at {}",
+					range.0,
+					range.1,
+					oldest
+				)),
+				_ => Ok(()),
+			}
+		};
+		check(&self.block_list())?;
+
+		let mut written: Vec<(BlockFile<File>, PathBuf)> = Vec::default();
+		let result = (|| -> Result<usize, anyhow::Error> {
+			let mut records = records.into_iter().peekable();
+			while records.peek().is_some() {
+				let mut chunk = Vec::default();
+				let mut size = 0;
+				while size < self.config.max_block_size {
+					match records.next() {
+						Some(record) => {
+							let record = record?;
+							size += record.size();
+							chunk.push(record);
+						}
+						None => break,
+					}
+				}
+				let block = InMemoryBlock::from_data(BlockData::from_records(chunk));
+				let (file, path) = self.try_write(&block)?;
+				log::info!(
+					"imported block: {} ({})",
+					path.display(),
+					format_bytes(file.size())
+				);
+				written.push((file, path));
+			}
+
+			let count = written.len();
+			let mut result = Ok(());
+			self.update_blocks(|blocks| {
+				// no pushes are stamped, until the files are published
+				let mut clock = self.clock.lock().unwrap();
+				result = check(blocks).and_then(|_| {
+					if clock.0 != seq && floor < range.1 {
+						return Err(anyhow::anyhow!(
+							"documents were pushed during the import of {}..{}",
+							range.0,
+							range.1
+						));
+					}
+					let edits = written
+						.iter()
+						.map(|(_, path)| Edit::Add(file_name(path)))
+						.collect();
+					// file is live only after it's in the manifest
+					self.manifest.lock().unwrap().apply(edits)
+				});
+				if result.is_err() {
+					return false;
+				}
+				let files = written
+					.drain(..)
+					.map(|(file, _)| Arc::new(RwLock::new(file)));
+				blocks.files.splice(0..0, files);
+				// later pushes can't get timestamps before the imported ones
+				clock.1 = std::cmp::max(clock.1, range.1);
+				return true;
+			});
+			return result.map(|_| count);
+		})();
+		if result.is_err() {
+			for (_, path) in written.iter() {
+				remove_file(path);
+			}
 		}
+		if let Ok(count) = result {
+			self.metrics.block_writes.add(count as u64);
+		}
+		return result;
 	}
 
-	fn save_active(self: &Arc<Self>) {
-		let mut active = self.active_block.write().unwrap();
-		if active.size() < self.config.max_active_size {
-			log::info!("missed saving active block");
-			return;
+	// sealed blocks are compacted here, and the big enough ones are written
+	// by up to `save_workers` blocking tasks in parallel.
+	// on the timer too old blocks are sealed and written, even if they are small
+	async fn save_worker(self: &Arc<Self>) {
+		let mut frozen = VecDeque::default();
+		let mut writes: FuturesUnordered<JoinHandle<()>> = FuturesUnordered::new();
+		let period = std::cmp::min(self.config.max_active_age, self.config.max_block_age) / 2;
+		let mut timer = tokio::time::interval(period);
+		while !self.stopped.load(Ordering::SeqCst) {
+			tokio::select! {
+				_ = self.bg_notify.notified() => {
+					let self_copy = Arc::clone(self);
+					let blocks = tokio::task::spawn_blocking(move || self_copy.save_sealed())
+						.await
+						.unwrap();
+					frozen.extend(blocks);
+				}
+				_ = timer.tick() => {
+					let self_copy = Arc::clone(self);
+					let blocks = tokio::task::spawn_blocking(move || self_copy.save_expired())
+						.await
+						.unwrap();
+					frozen.extend(blocks);
+				}
+				Some(result) = writes.next(), if !writes.is_empty() => {
+					result.unwrap();
+				}
+			}
+			while writes.len() < self.config.save_workers {
+				let block = match frozen.pop_front() {
+					Some(block) => block,
+					None => break,
+				};
+				let self_copy = Arc::clone(self);
+				writes.push(tokio::task::spawn_blocking(move || {
+					self_copy.write_frozen(block)
+				}));
+			}
 		}
+		while let Some(result) = writes.next().await {
+			result.unwrap();
+		}
+	}
 
-		log::info!("saving active block");
-		let old_active = std::mem::take(active.as_mut());
+	// returns blocks, that have to be written
+	fn save_sealed(self: &Arc<Self>) -> Vec<Arc<RwLock<InMemoryBlock>>> {
+		let mut frozen = Vec::default();
+		loop {
+			let moved = self.update_blocks(|blocks| {
+				if blocks.sealed.is_empty() {
+					return false;
+				}
+				let block = blocks.sealed.remove(0);
+				blocks.in_memory.push(block);
+				return true;
+			});
+			if !moved {
+				if self.flush_until.load(Ordering::SeqCst) > 0 {
+					// snapshot waits for the in memory blocks, even without new sealed ones
+					frozen.extend(self.freeze());
+				}
+				return frozen;
+			}
+			log::info!("saving sealed block");
 
-		let mut compact_list = self.compact_list.write().unwrap();
-		std::mem::drop(active);
+			self.compact();
+			frozen.extend(self.freeze());
 
-		let block = old_active.into_block();
-		compact_list.push(Arc::new(RwLock::new(block)));
+			// writers could wait for the place in the sealed list
+			if self.active_size.load(Ordering::SeqCst) >= self.config.max_active_size
+				&& self.seal_active(Seal::Full)
+			{
+				self.active_notify.notify_waiters();
+			}
+		}
+	}
 
-		let new_block = self.compact(compact_list.as_mut());
-		// guarantee, that new_block will not be lost while iterating
-		// TODO: implement queue (look in todo in write_block)
-		let mut block_files = self.block_files.write().unwrap();
-		std::mem::drop(compact_list);
-		if let Some(new_block) = new_block {
-			self.write_block(new_block, block_files.as_mut());
+	// returns blocks, that have to be written, because they are too old
+	fn save_expired(self: &Arc<Self>) -> Vec<Arc<RwLock<InMemoryBlock>>> {
+		if self.seal_active(Seal::Expired) {
+			log::info!("sealed expired active block");
+			self.active_notify.notify_waiters();
 		}
+		let mut frozen = self.save_sealed();
+		// in memory blocks could expire without the new sealed blocks
+		frozen.extend(self.freeze());
+		return frozen;
 	}
 
-	fn compact(
-		&self,
-		compact_list: &mut Vec<Arc<RwLock<InMemoryBlock>>>,
-	) -> Option<Box<InMemoryBlock>> {
+	// only the save worker changes not frozen in memory blocks, so they are merged
+	// without any locks and the result is published at once
+	fn compact(&self) {
 		log::info!("compaction started");
+		let start = Instant::now();
+		let mut compact_list = {
+			let writing = self.writing.lock().unwrap();
+			self.block_list().in_memory[writing.len()..].to_vec()
+		};
+		let first = match compact_list.first() {
+			Some(first) => Arc::clone(first),
+			None => return,
+		};
 		let start_size = compact_list.len();
-		// we take locks here, but we actually have gurantee, that they are free
-		// so may be change this to unsafe later
 		while let [.., prev, last] = &compact_list[..] {
 			let need_merge = {
 				let prev = prev.read().unwrap();
@@ -304,80 +1234,189 @@ impl Storage {
 			if !need_merge {
 				break;
 			}
-			let last = Arc::try_unwrap(compact_list.pop().unwrap())
-				.unwrap()
-				.into_inner()
-				.unwrap();
-			let prev = Arc::try_unwrap(compact_list.pop().unwrap())
-				.unwrap()
-				.into_inner()
-				.unwrap();
+			let last = take_block(compact_list.pop().unwrap());
+			let prev = take_block(compact_list.pop().unwrap());
 			let new_block = prev.merge(last);
 			compact_list.push(Arc::new(RwLock::new(new_block)));
 		}
-		log::info!(
-			"compaction ended: compacted {} blocks",
-			start_size - compact_list.len()
-		);
-		let need_write = compact_list
-			.last()
-			.map(|block| block.read().unwrap().size() > self.config.max_block_size)
-			.unwrap_or(false);
-		if need_write {
-			let block = Arc::try_unwrap(compact_list.pop().unwrap())
-				.unwrap()
-				.into_inner()
-				.unwrap();
-			return Some(Box::new(block));
+		let compacted = start_size - compact_list.len();
+		if compacted > 0 {
+			self.update_blocks(|blocks| {
+				// frozen blocks before could be written in the meantime
+				let start = blocks
+					.in_memory
+					.iter()
+					.position(|block| Arc::ptr_eq(block, &first))
+					.unwrap();
+				blocks.in_memory.truncate(start);
+				blocks.in_memory.append(&mut compact_list);
+				return true;
+			});
 		}
-		return None;
+		if compacted > 0 {
+			self.metrics.compactions.add(1);
+			self.metrics.compacted_blocks.add(compacted as u64);
+		}
+		self.metrics.compaction_duration.observe(start.elapsed());
+		log::info!("compaction ended: compacted {} blocks", compacted);
 	}
 
-	fn write_block(
-		&self,
-		block: Box<InMemoryBlock>,
-		block_files: &mut Vec<Arc<RwLock<BlockFile<File>>>>,
-	) {
+	// freezes big enough or too old in memory blocks, so they aren't compacted anymore, and returns
+	// them together with the ones, that failed to be written, to be written again
+	fn freeze(&self) -> Vec<Arc<RwLock<InMemoryBlock>>> {
+		let mut writing = self.writing.lock().unwrap();
+		let mut result = Vec::default();
+		for (block, state) in writing.iter_mut() {
+			if matches!(state, WriteState::Failed) {
+				*state = WriteState::Writing;
+				result.push(Arc::clone(block));
+			}
+		}
+		let blocks = self.block_list();
+		// only the oldest blocks can become big or old enough, so frozen blocks are always
+		// in front
+		for block in blocks.in_memory[writing.len()..]
+			.iter()
+			.take_while(|block| {
+				let block = block.read().unwrap();
+				block.size() > self.config.max_block_size
+					|| self.is_expired(block.range().0, self.config.max_block_age)
+					|| block.range().0 < self.flush_until.load(Ordering::SeqCst)
+			}) {
+			writing.push_back((Arc::clone(block), WriteState::Writing));
+			result.push(Arc::clone(block));
+		}
+		return result;
+	}
+
+	fn write_frozen(&self, block: Arc<RwLock<InMemoryBlock>>) {
 		log::info!("writing block on disk");
-		let result = self.try_write(block);
-		if result.is_err() {
-			// this is bad, because we'll just lose this block until next restart
-			// when it will be read from WAL
-			// TODO: save block in some queue, return blocks from it in search
-			// TODO: and try to rewrite them
-			log::error!("can't write block: {}", result.err().unwrap());
+		let result = self.try_write(&block.read().unwrap());
+
+		let mut writing = self.writing.lock().unwrap();
+		let (_, state) = writing
+			.iter_mut()
+			.find(|(frozen, _)| Arc::ptr_eq(frozen, &block))
+			.unwrap();
+		*state = match result {
+			Ok((file, path)) => {
+				log::info!(
+					"writing block on disk: {} ({})",
+					path.display(),
+					format_bytes(file.size())
+				);
+				self.metrics.block_writes.add(1);
+				WriteState::Written(Box::new(file), path)
+			}
+			Err(err) => {
+				// block stays in memory, we'll try to write it after the next sealed block
+				log::error!("can't write block: {}", err);
+				self.metrics.write_failures.add(1);
+				WriteState::Failed
+			}
+		};
+
+		// files must stay ordered, so only the written blocks from the front are published
+		let written = writing
+			.iter()
+			.take_while(|(_, state)| matches!(state, WriteState::Written(..)))
+			.count();
+		if written == 0 {
 			return;
 		}
-		let block = result.unwrap();
-		debug_assert!(
-			block_files.is_empty()
-				|| block_files.last().unwrap().read().unwrap().range().1 <= block.range().0
-		);
-		block_files.push(Arc::new(RwLock::new(block)));
-		log::info!("writing block on disk: success");
+		let edits = writing
+			.iter()
+			.take(written)
+			.map(|(_, state)| match state {
+				WriteState::Written(_, path) => Edit::Add(file_name(path)),
+				_ => unreachable!(),
+			})
+			.collect();
+		// file is live only after it's in the manifest
+		if let Err(err) = self.manifest.lock().unwrap().apply(edits) {
+			log::error!("can't update manifest: {}", err);
+			self.metrics.write_failures.add(written as u64);
+			for (_, state) in writing.iter_mut().take(written) {
+				if let WriteState::Written(_, path) = state {
+					remove_file(path);
+				}
+				*state = WriteState::Failed;
+			}
+			return;
+		}
+
+		let written: Vec<_> = writing.drain(..written).collect();
+		self.update_blocks(|blocks| {
+			for (block, state) in written {
+				let file = match state {
+					WriteState::Written(file, _) => *file,
+					_ => unreachable!(),
+				};
+				debug_assert!(Arc::ptr_eq(&blocks.in_memory[0], &block));
+				debug_assert!(
+					blocks.files.is_empty()
+						|| blocks.files.last().unwrap().read().unwrap().range().1 <= file.range().0
+				);
+				blocks.in_memory.remove(0);
+				blocks.files.push(Arc::new(RwLock::new(file)));
+			}
+			return true;
+		});
 	}
 
-	fn try_write(&self, block: Box<InMemoryBlock>) -> Result<BlockFile<File>, anyhow::Error> {
+	// names are unique only inside the process, so if another one
+	// has already taken the name, the next sequence number is tried
+	fn try_write(
+		&self,
+		block: &InMemoryBlock,
+	) -> Result<(BlockFile<File>, PathBuf), anyhow::Error> {
+		loop {
+			let path = self.name_file(block.range());
+			match self.write_file(block, &path) {
+				Err(err) if is_already_exists(&err) => {
+					log::warn!("{} already exists", path.display());
+				}
+				result => return result.map(|file| (file, path)),
+			}
+		}
+	}
+
+	// block is written in the temporary file, that is linked to the final name after it is
+	// synced, so only complete block files can be found with the index extension
+	fn write_file(
+		&self,
+		block: &InMemoryBlock,
+		path: &Path,
+	) -> Result<BlockFile<File>, anyhow::Error> {
+		let tmp_path = path.with_extension("tmp");
 		let file = File::options()
-			.create(true)
-			.truncate(true)
+			.create_new(true)
 			.read(true)
 			.write(true)
-			.open(self.name_file(block.range().0))?;
-		return block.write(file).map_err(|(_, err)| err);
+			.open(&tmp_path)?;
+		let result = (|| -> Result<BlockFile<File>, anyhow::Error> {
+			let sync = file.try_clone()?;
+			// only the header stays in memory, everything else is loaded on demand
+			let block = block.write_unloaded(file)?;
+			sync.sync_all()?;
+			// unlike rename, fails if the file exists, and opened file stays valid
+			std::fs::hard_link(&tmp_path, path)?;
+			return Ok(block);
+		})();
+		remove_file(&tmp_path);
+		let block = result?;
+		File::open(&self.config.data_dir)?.sync_all()?;
+		return Ok(block);
 	}
 
-	fn name_file(&self, ts: Timestamp) -> PathBuf {
-		let ts = uuid::v1::Timestamp::from_unix(
-			self.context.as_ref(),
-			ts / 1000,
-			(ts % 1000 * 1_000_000) as u32,
-		);
-		let id = uuid::Uuid::new_v1(ts, &[0, 0, 0, 0, 0, 0])
-			.unwrap()
-			.to_hyphenated()
-			.to_string();
-		return self.config.data_dir.join(id).with_extension("index");
+	fn name_file(&self, range: (Timestamp, Timestamp)) -> PathBuf {
+		let name = BlockName {
+			level: FLUSH_LEVEL,
+			from: range.0,
+			to: range.1,
+			seq: self.next_seq.fetch_add(1, Ordering::SeqCst),
+		};
+		return self.config.data_dir.join(name.to_string());
 	}
 }
 
@@ -1,4 +1,5 @@
 use super::*;
+use crate::storage::{Query, MAX_TIME, MIN_TIME};
 use crate::tests;
 use std::io::Cursor;
 use std::time::*;
@@ -21,20 +22,30 @@ fn basic() -> Result<(), anyhow::Error> {
 			keys: vec_str!["key0", "key1"],
 			timestamps: vec![100, 300],
 			index: vec_arc![vec![0], vec![0, 1], vec![1]],
+			..Default::default()
 		};
 		let mut buf = Cursor::new(vec![0; 128]);
 		let block = block.write(&mut buf).map_err(|(_, err)| err)?;
 		let (mut buf, _, data) = block.release_all();
 
 		let header = BlockHeader::read_header(&mut buf, 0)?;
-		let mut read_block = header.read_meta(buf)?;
+		let mut read_block = header.open(buf);
+		assert!(read_block.may_contain("tag0"));
+		assert!(read_block.may_contain("tag2"));
+		assert!(!read_block.may_contain("key0"));
+		assert_eq!(read_block.get_range(), Some((100, 300)));
+		for column in COLUMNS {
+			read_block.read_column(column)?;
+		}
 		read_block.read_index(0)?;
 		read_block.read_index(1)?;
 		read_block.read_index(2)?;
 
-		assert_eq!(data, read_block.data);
-		assert_eq!(read_block.header.from, 100);
-		assert_eq!(read_block.header.to, 300);
+		let (_, header, read_data) = read_block.release_all();
+		assert_eq!(data, read_data);
+		assert_eq!(header.from, 100);
+		assert_eq!(header.to, 300);
+		assert_eq!(header.count, 2);
 
 		Ok(())
 	})
@@ -48,26 +59,34 @@ fn header_size() -> Result<(), anyhow::Error> {
 		let buf = rmp_serde::to_vec(&header)?;
 		assert!((buf.len() as u64) <= super::header_size(header.index.len()));
 
-		header.tags = std::u64::MAX;
-		header.keys = std::u64::MAX;
-		header.timestamps = std::u64::MAX;
+		header.tags = u64::MAX;
+		header.keys = u64::MAX;
+		header.timestamps = u64::MAX;
 
 		let buf = rmp_serde::to_vec(&header)?;
 		assert!((buf.len() as u64) <= super::header_size(header.index.len()));
 
-		header.index.resize(1, std::u64::MAX);
+		header.index.resize(1, u64::MAX);
+		header.lengths.resize(1, u64::MAX);
+		header.bloom = Bloom::filled(1);
 		let buf = rmp_serde::to_vec(&header)?;
 		assert!((buf.len() as u64) <= super::header_size(header.index.len()));
 
-		header.index.resize(16, std::u64::MAX);
+		header.index.resize(16, u64::MAX);
+		header.lengths.resize(16, u64::MAX);
+		header.bloom = Bloom::filled(16);
 		let buf = rmp_serde::to_vec(&header)?;
 		assert!((buf.len() as u64) <= super::header_size(header.index.len()));
 
-		header.index.resize(1024, std::u64::MAX);
+		header.index.resize(1024, u64::MAX);
+		header.lengths.resize(1024, u64::MAX);
+		header.bloom = Bloom::filled(1024);
 		let buf = rmp_serde::to_vec(&header)?;
 		assert!((buf.len() as u64) <= super::header_size(header.index.len()));
 
-		header.index.resize(1024 * 1024, std::u64::MAX);
+		header.index.resize(1024 * 1024, u64::MAX);
+		header.lengths.resize(1024 * 1024, u64::MAX);
+		header.bloom = Bloom::filled(1024 * 1024);
 		let buf = rmp_serde::to_vec(&header)?;
 		assert!((buf.len() as u64) <= super::header_size(header.index.len()));
 
@@ -103,13 +122,10 @@ fn data() -> Result<(), anyhow::Error> {
 		let (mut buf, _, data) = block.release_all();
 
 		let header = BlockHeader::read_header(&mut buf, 0)?;
-		let mut read_block = header.read_meta(buf)?;
-		let ind_size = read_block.header.index.len();
-		for i in 0..ind_size {
-			read_block.read_index(i)?;
-		}
+		let mut read_block = header.open(buf);
+		read_block.read_all()?;
 
-		assert_eq!(data, read_block.data);
+		assert_eq!(data, read_block.release_all().2);
 
 		Ok(())
 	})
@@ -124,9 +140,11 @@ fn empty() -> Result<(), anyhow::Error> {
 		let (mut buf, _, data) = block.release_all();
 
 		let header = BlockHeader::read_header(&mut buf, 0)?;
-		let read_block = header.read_meta(buf)?;
+		let mut read_block = header.open(buf);
+		assert_eq!(read_block.get_range(), None);
+		read_block.read_all()?;
 
-		assert_eq!(data, read_block.data);
+		assert_eq!(data, read_block.release_all().2);
 
 		Ok(())
 	})
@@ -155,9 +173,12 @@ fn active() -> Result<(), anyhow::Error> {
 			keys: vec_str!["key0", "key1", "key2", "key3", "key4", "key5"],
 			timestamps: block.data.timestamps.clone(),
 			index: vec_arc![vec![0, 2, 3, 5], vec![0, 1, 5], vec![3], vec![1], vec![3]],
+			..Default::default()
 		};
 		assert_eq!(block.data, expected);
-		assert_eq!(block.size, 10);
+		// keys + timestamps + postings + tags
+		assert_eq!(block.size, 6 * 4 + 6 * 8 + 10 * 8 + 5 * 4);
+		assert_eq!(block.size, block.data.size());
 
 		for t in block.data.timestamps {
 			let cur = SystemTime::UNIX_EPOCH + Duration::from_millis(t);
@@ -170,6 +191,57 @@ fn active() -> Result<(), anyhow::Error> {
 	})
 }
 
+#[test]
+fn duplicate_tags() -> Result<(), anyhow::Error> {
+	tests::run_with_logger(|| {
+		let mut active = ActiveBlock::default();
+		active.push_at(1, 100, "key0".to_string(), vec_str!["tag1", "tag0", "tag1"]);
+		active.push_at(2, 200, "key1".to_string(), vec_str!["tag0", "tag0"]);
+
+		let view = ActiveView::new(Arc::new(std::sync::RwLock::new(active.clone())));
+		assert_eq!(*view.try_get_index(0).unwrap(), vec![0, 1]);
+		let block: Arc<std::sync::RwLock<dyn SearchBlock>> = Arc::new(std::sync::RwLock::new(view));
+		let query = Query::new(vec_str!["tag0", "tag1"], (MIN_TIME, MAX_TIME));
+		assert_eq!(query.execute(&block)?, vec![0]);
+
+		let block = active.into_block();
+		assert_eq!(block.data.index, vec_arc![vec![0, 1], vec![0]]);
+		// postings are counted once
+		assert_eq!(block.size, 2 * 4 + 2 * 8 + 3 * 8 + 2 * 4);
+		assert_eq!(block.size, block.data.size());
+
+		Ok(())
+	})
+}
+
+#[test]
+fn active_shards() -> Result<(), anyhow::Error> {
+	tests::run_with_logger(|| {
+		let mut first = ActiveBlock::default();
+		let mut second = ActiveBlock::default();
+		first.push_at(1, 100, "key0".to_string(), vec_str!["tag0", "tag1"]);
+		second.push_at(2, 100, "key1".to_string(), vec_str!["tag1"]);
+		second.push_at(2, 100, "key2".to_string(), vec_str!["tag0"]);
+		first.push_at(3, 200, "key3".to_string(), vec_str!["tag2"]);
+		second.push_at(4, 250, "key4".to_string(), vec_str!["tag0"]);
+
+		let block = ActiveBlock::merge(vec![first, ActiveBlock::default(), second]).into_block();
+		let expected = BlockData {
+			tags: vec_str!["tag0", "tag1", "tag2"],
+			keys: vec_str!["key0", "key1", "key2", "key3", "key4"],
+			timestamps: vec![100, 100, 100, 200, 250],
+			index: vec_arc![vec![0, 2, 4], vec![0, 1], vec![3]],
+			..Default::default()
+		};
+		assert_eq!(block.data, expected);
+		// shared tags are counted once
+		assert_eq!(block.size, 5 * 4 + 5 * 8 + 6 * 8 + 3 * 4);
+		assert_eq!(block.size, block.data.size());
+
+		Ok(())
+	})
+}
+
 #[test]
 fn merge() -> Result<(), anyhow::Error> {
 	tests::run_with_logger(|| {
@@ -186,7 +258,9 @@ fn merge() -> Result<(), anyhow::Error> {
 		second.push("key6".to_string(), vec_str!["tag5"]);
 		let second = second.into_block();
 
-		let block = first.merge(second).data;
+		let block = first.merge(second);
+		assert_eq!(block.size(), 7 * 4 + 7 * 8 + 12 * 8 + 6 * 4);
+		let block = block.data;
 
 		let expected = BlockData {
 			tags: vec_str!["tag0", "tag1", "tag2", "tag3", "tag4", "tag5"],
@@ -200,6 +274,7 @@ fn merge() -> Result<(), anyhow::Error> {
 				vec![3],
 				vec![6],
 			],
+			..Default::default()
 		};
 		assert_eq!(expected.tags, block.tags);
 		assert_eq!(expected.keys, block.keys);
@@ -247,40 +322,295 @@ fn two_blocks_in_one_file() -> Result<(), anyhow::Error> {
 		let second = second.into_block();
 
 		let file = Cursor::new(vec![]);
-		let first = first.write(file).map_err(|(_, err)| err)?;
-		let file = first.file;
-		assert_eq!(first.header.start, 0);
-		assert_ne!(first.header.size, 0);
+		let (file, first_header, first_data) =
+			first.write(file).map_err(|(_, err)| err)?.release_all();
+		assert_eq!(first_header.start, 0);
+		assert_ne!(first_header.size, 0);
 		assert_eq!(
 			file.get_ref().len() as u64,
-			first.header.start + first.header.size
+			first_header.start + first_header.size
 		);
 
-		let second = second.write(file).map_err(|(_, err)| err)?;
-		let mut file = second.file;
-		assert_eq!(second.header.start, first.header.start + first.header.size);
-		assert_ne!(second.header.size, 0);
+		let (mut file, second_header, second_data) =
+			second.write(file).map_err(|(_, err)| err)?.release_all();
+		assert_eq!(second_header.start, first_header.start + first_header.size);
+		assert_ne!(second_header.size, 0);
 		assert_eq!(
 			file.get_ref().len() as u64,
-			second.header.start + second.header.size
+			second_header.start + second_header.size
 		);
 
 		let check = |mut file: Cursor<Vec<u8>>,
 		             header: &BlockHeader,
 		             data: &BlockData|
 		 -> Result<Cursor<Vec<u8>>, anyhow::Error> {
-			let mut read = BlockHeader::read_header(&mut file, header.start)?.read_meta(file)?;
+			let mut read = BlockHeader::read_header(&mut file, header.start)?.open(file);
 
 			read.read_all()?;
 
-			assert_eq!(*header, read.header);
-			assert_eq!(*data, read.data);
+			let (file, read_header, read_data) = read.release_all();
+			assert_eq!(*header, read_header);
+			assert_eq!(*data, read_data);
+
+			Ok(file)
+		};
+
+		file = check(file, &first_header, &first_data)?;
+		check(file, &second_header, &second_data)?;
+
+		Ok(())
+	})
+}
+
+#[test]
+fn lazy() -> Result<(), anyhow::Error> {
+	tests::run_with_logger(|| {
+		let mut active = ActiveBlock::default();
+		active.push("key0".to_string(), vec_str!["tag0", "tag1"]);
+		active.push("key1".to_string(), vec_str!["tag1"]);
+		let (mut file, _, data) = active
+			.into_block()
+			.write(Cursor::new(vec![]))
+			.map_err(|(_, err)| err)?
+			.release_all();
+
+		let mut block = BlockHeader::read_header(&mut file, 0)?.open(file);
+		for column in COLUMNS {
+			assert!(!block.is_loaded(column));
+		}
+		assert_eq!(block.get_stats().memory, 0);
+		assert_eq!(block.get_index_len(1), 2);
+		assert_eq!(block.get_rows((MIN_TIME, MAX_TIME)), 0..2);
+
+		block.read_column(Column::Keys)?;
+		// loaded columns take memory without any index
+		assert!(block.get_stats().memory > 0);
+		assert_eq!(block.get_stats().loaded_indexes, 0);
+		assert!(block.is_loaded(Column::Keys));
+		assert!(!block.is_loaded(Column::Tags));
+		assert_eq!(block.get_key(0), data.keys[0]);
+		assert_eq!(block.get_key(1), data.keys[1]);
+
+		block.read_all()?;
+		block.unload();
+		for column in COLUMNS {
+			assert!(!block.is_loaded(column));
+		}
+		assert_eq!(block.get_stats().memory, 0);
+		assert!(block.try_get_index(0).is_none());
+
+		block.read_column(Column::Tags)?;
+		assert_eq!(block.get_tags(), data.tags);
 
-			Ok(read.file)
+		Ok(())
+	})
+}
+
+#[test]
+fn records() -> Result<(), anyhow::Error> {
+	tests::run_with_logger(|| {
+		let mut active = ActiveBlock::default();
+		active.push_at(1, 100, "key0".to_string(), vec_str!["tag1", "tag0"]);
+		active.push_at(2, 200, "key1".to_string(), vec_str![]);
+		active.push_at(3, 300, "key2".to_string(), vec_str!["tag1"]);
+		let (mut file, _, _) = active
+			.into_block()
+			.write(Cursor::new(vec![]))
+			.map_err(|(_, err)| err)?
+			.release_all();
+
+		// documents without tags are kept too
+		let mut block = BlockHeader::read_header(&mut file, 0)?.open(file);
+		assert_eq!(block.header().count, 3);
+		let record = |key: &str, tags: Vec<String>, timestamp| Record {
+			key: key.to_string(),
+			tags,
+			timestamp,
+			payload: None,
+		};
+		assert_eq!(
+			read_records(&mut block)?,
+			[
+				record("key0", vec_str!["tag0", "tag1"], 100),
+				record("key1", vec_str![], 200),
+				record("key2", vec_str!["tag1"], 300),
+			]
+		);
+
+		Ok(())
+	})
+}
+
+#[test]
+fn payloads() -> Result<(), anyhow::Error> {
+	tests::run_with_logger(|| {
+		let payload = |value: serde_json::Value| encode_payload(Some(&value));
+		let mut shards = vec![ActiveBlock::default(), ActiveBlock::default()];
+		shards[0].push_at(1, 100, "key0".to_string(), vec_str!["tag0"]);
+		shards[1].push_with_payload(
+			2,
+			200,
+			"key1".to_string(),
+			vec_str!["tag0"],
+			payload(serde_json::json!({"body": "text"})),
+		);
+		shards[0].push_at(3, 300, "key2".to_string(), vec_str!["tag1"]);
+		let block = ActiveBlock::merge(shards).into_block();
+		assert_eq!(block.get_payload(0), None);
+		assert_eq!(
+			block.get_payload(1),
+			Some(serde_json::json!({"body": "text"}))
+		);
+
+		// block without payloads is merged into the one with them
+		let mut old = ActiveBlock::default();
+		old.push_at(0, 50, "old".to_string(), vec_str!["tag0"]);
+		let block = old.into_block().merge(block);
+		assert_eq!(block.get_payload(0), None);
+		assert_eq!(
+			block.get_payload(2),
+			Some(serde_json::json!({"body": "text"}))
+		);
+
+		let (file, header, _) = block
+			.write(Cursor::new(vec![]))
+			.map_err(|(_, err)| err)?
+			.release_all();
+		assert!(header.payloads > *header.index.last().unwrap());
+		let len = file.get_ref().len() as u64;
+		let mut read = header.open(file);
+		assert_eq!(read.verify(len), Vec::<String>::new());
+		let payloads: Vec<_> = read_records(&mut read)?
+			.into_iter()
+			.map(|record| record.payload)
+			.collect();
+		assert_eq!(
+			payloads,
+			[None, None, Some(serde_json::json!({"body": "text"})), None]
+		);
+
+		// blocks without payloads don't have the column, like the older ones
+		let mut active = ActiveBlock::default();
+		active.push_at(1, 100, "key0".to_string(), vec_str!["tag0"]);
+		let block = active
+			.into_block()
+			.write(Cursor::new(vec![]))
+			.map_err(|(_, err)| err)?;
+		assert_eq!(block.header().payloads, 0);
+		let (file, header, _) = block.release_all();
+		let mut read = header.open(file);
+		read.read_column(Column::Payloads)?;
+		assert_eq!(read.get_payload(0), None);
+
+		Ok(())
+	})
+}
+
+#[test]
+fn verify() -> Result<(), anyhow::Error> {
+	tests::run_with_logger(|| {
+		let mut active = ActiveBlock::default();
+		active.push_at(1, 100, "key0".to_string(), vec_str!["tag0", "tag1"]);
+		active.push_at(2, 200, "key1".to_string(), vec_str!["tag1"]);
+		let (file, header, _) = active
+			.into_block()
+			.write(Cursor::new(vec![]))
+			.map_err(|(_, err)| err)?
+			.release_all();
+		let len = file.get_ref().len() as u64;
+		let check = |header: &BlockHeader| header.clone().open(file.clone()).verify(len);
+		assert_eq!(check(&header), Vec::<String>::new());
+
+		let mut broken = header.clone();
+		broken.count = 1;
+		let problems = check(&broken);
+		assert!(problems
+			.iter()
+			.any(|p| p.contains("2 keys for 1 documents")));
+		assert!(problems
+			.iter()
+			.any(|p| p.contains("has posting 1 for 1 documents")));
+
+		let mut broken = header.clone();
+		broken.from = 150;
+		assert!(check(&broken)[0].contains("don't match the header range"));
+
+		let mut broken = header.clone();
+		broken.keys = broken.tags;
+		assert!(check(&broken)[0].contains("keys offset"));
+
+		let mut broken = header.clone();
+		broken.size += 1;
+		assert!(check(&broken)[0].contains("after the end of the file"));
+
+		// columns are decoded, so garbage inside them is found before the queries panic
+		let corrupt = |offset: u64, expected: u8, value: u8| {
+			let mut file = file.clone();
+			let byte = &mut file.get_mut()[offset as usize];
+			assert_eq!(*byte, expected);
+			*byte = value;
+			header.clone().open(file).verify(len)
 		};
+		// [count, [restart], data]
+		let problems = corrupt(header.keys + 3, 0, 0x7f);
+		assert!(problems[0].contains("broken keys"), "{:?}", problems);
+		// [count, [first], [offset], data]
+		let problems = corrupt(header.timestamps + 5, 0, 0x05);
+		assert!(problems[0].contains("broken timestamps"), "{:?}", problems);
+
+		Ok(())
+	})
+}
 
-		file = check(file, &first.header, &first.data)?;
-		check(file, &second.header, &second.data)?;
+#[test]
+fn active_view() -> Result<(), anyhow::Error> {
+	tests::run_with_logger(|| {
+		let active = Arc::new(std::sync::RwLock::new(ActiveBlock::default()));
+		active
+			.write()
+			.unwrap()
+			.push_at(1, 100, "key0".to_string(), vec_str!["tag0", "tag1"]);
+		active
+			.write()
+			.unwrap()
+			.push_at(2, 200, "key1".to_string(), vec_str!["tag1"]);
+
+		let view = ActiveView::new(Arc::clone(&active));
+		active
+			.write()
+			.unwrap()
+			.push_at(3, 300, "key2".to_string(), vec_str!["tag1", "tag2"]);
+
+		assert_eq!(view.get_tags(), vec_str!["tag0", "tag1"]);
+		assert!(!view.may_contain("tag2"));
+		assert_eq!(view.get_range(), Some((100, 200)));
+		assert_eq!(view.get_rows((MIN_TIME, MAX_TIME)), 0..2);
+		assert_eq!(view.get_index_len(1), 2);
+		assert_eq!(*view.try_get_index(1).unwrap(), vec![0, 1]);
+		assert_eq!(view.get_key(1), "key1");
+
+		let view = ActiveView::new(Arc::clone(&active));
+		assert_eq!(view.get_tags(), vec_str!["tag0", "tag1", "tag2"]);
+		assert_eq!(*view.try_get_index(1).unwrap(), vec![0, 1, 2]);
+		// postings and tags are shared, not copied
+		let postings = view.try_get_index(1).unwrap();
+		assert!(Arc::ptr_eq(
+			&postings.index,
+			&active.read().unwrap().index["tag1"]
+		));
+		assert!(Arc::ptr_eq(&view.tags, &active.read().unwrap().sorted_tags));
+
+		// the writer copies only the postings, that are still held
+		active
+			.write()
+			.unwrap()
+			.push_at(4, 400, "key3".to_string(), vec_str!["tag1", "tag3"]);
+		assert_eq!(*postings, vec![0, 1, 2]);
+		assert_eq!(*view.try_get_index(1).unwrap(), vec![0, 1, 2]);
+		assert_eq!(view.get_tags(), vec_str!["tag0", "tag1", "tag2"]);
+		let view = ActiveView::new(Arc::clone(&active));
+		assert_eq!(view.get_tags(), vec_str!["tag0", "tag1", "tag2", "tag3"]);
+		assert_eq!(*view.try_get_index(1).unwrap(), vec![0, 1, 2, 3]);
 
 		Ok(())
 	})
@@ -0,0 +1,39 @@
+use super::*;
+use crate::tests;
+
+#[test]
+fn basic() -> Result<(), anyhow::Error> {
+	tests::run_with_logger(|| {
+		let items: Vec<String> = (0..1000).map(|i| format!("tag{}", i)).collect();
+		let bloom = Bloom::from_items(items.iter());
+
+		for item in items.iter() {
+			assert!(bloom.contains(item), "false negative for {}", item);
+		}
+
+		let false_positives = (0..10000)
+			.map(|i| format!("other{}", i))
+			.filter(|item| bloom.contains(item))
+			.count();
+		assert!(false_positives < 300, "{} false positives", false_positives);
+
+		let buf = rmp_serde::to_vec(&bloom)?;
+		assert_eq!(rmp_serde::from_read_ref::<_, Bloom>(&buf)?, bloom);
+
+		Ok(())
+	})
+}
+
+#[test]
+fn empty() -> Result<(), anyhow::Error> {
+	tests::run_with_logger(|| {
+		let bloom = Bloom::from_items(Vec::<String>::new().iter());
+		assert!(!bloom.contains("tag"));
+		assert!(!Bloom::default().contains("tag"));
+
+		let bloom = Bloom::from_items(["tag".to_string()].iter());
+		assert!(bloom.contains("tag"));
+
+		Ok(())
+	})
+}
@@ -0,0 +1,201 @@
+use super::*;
+use crate::tests;
+
+fn urls(count: usize) -> Vec<String> {
+	(0..count)
+		.map(|i| format!("https://example.com/users/{}/events/{}", i / 100, i))
+		.collect()
+}
+
+#[test]
+fn varint() -> Result<(), anyhow::Error> {
+	tests::run_with_logger(|| {
+		let values = [0, 1, 127, 128, 300, 1 << 35, u64::MAX];
+		let mut buf = Vec::default();
+		for value in values {
+			write_varint(&mut buf, value);
+		}
+		let mut pos = 0;
+		for value in values {
+			let (read, next) = read_varint(&buf, pos);
+			assert_eq!(read, value);
+			pos = next;
+		}
+		assert_eq!(pos, buf.len());
+
+		Ok(())
+	})
+}
+
+#[test]
+fn keys() -> Result<(), anyhow::Error> {
+	tests::run_with_logger(|| {
+		let mut keys = urls(1000);
+		keys.push("".to_string());
+		keys.push("ключ".to_string());
+		keys.push("ключи".to_string());
+		keys.push("кл".to_string());
+
+		let column = KeyColumn::encode(&keys);
+		assert_eq!(column.len(), keys.len());
+		assert_eq!(column.decode_all(), keys);
+		for (i, key) in keys.iter().enumerate() {
+			assert_eq!(column.get(i), *key);
+		}
+
+		let buf = rmp_serde::to_vec(&column)?;
+		assert_eq!(rmp_serde::from_read_ref::<_, KeyColumn>(&buf)?, column);
+
+		Ok(())
+	})
+}
+
+#[test]
+fn keys_size() -> Result<(), anyhow::Error> {
+	tests::run_with_logger(|| {
+		let keys = urls(10000);
+		let plain = rmp_serde::to_vec(&keys)?.len();
+		let compressed = rmp_serde::to_vec(&KeyColumn::encode(&keys))?.len();
+		assert!(compressed * 2 < plain, "{} vs {}", compressed, plain);
+
+		let empty = KeyColumn::encode(&[]);
+		assert!(empty.is_empty());
+		assert_eq!(empty.decode_all(), Vec::<String>::new());
+
+		Ok(())
+	})
+}
+
+#[test]
+fn timestamps() -> Result<(), anyhow::Error> {
+	tests::run_with_logger(|| {
+		let mut timestamps = Vec::default();
+		let mut ts = 1_600_000_000_000;
+		for i in 0..1000 {
+			// some timestamps are equal, and some are far apart
+			ts += (i % 3) * (i % 7) * 10;
+			timestamps.push(ts);
+		}
+
+		let column = TimestampColumn::encode(&timestamps);
+		assert_eq!(column.len(), timestamps.len());
+		assert_eq!(column.decode_all(), timestamps);
+		for (i, ts) in timestamps.iter().enumerate() {
+			assert_eq!(column.get(i), *ts);
+		}
+
+		let rows = |range: (Timestamp, Timestamp)| {
+			let start = timestamps.partition_point(|ts| *ts < range.0);
+			let end = timestamps.partition_point(|ts| *ts <= range.1);
+			start..std::cmp::max(start, end)
+		};
+		let first = timestamps[0];
+		let last = *timestamps.last().unwrap();
+		for range in [
+			(0, u64::MAX),
+			(0, first - 1),
+			(last + 1, u64::MAX),
+			(first, first),
+			(last, last),
+			(timestamps[127], timestamps[128]),
+			(timestamps[128], timestamps[128]),
+			(timestamps[300] + 1, timestamps[700] - 1),
+			(timestamps[500], timestamps[499]),
+		] {
+			assert_eq!(column.rows(range), rows(range), "{:?}", range);
+		}
+
+		let buf = rmp_serde::to_vec(&column)?;
+		assert_eq!(
+			rmp_serde::from_read_ref::<_, TimestampColumn>(&buf)?,
+			column
+		);
+		assert!(buf.len() * 4 < rmp_serde::to_vec(&timestamps)?.len());
+
+		let empty = TimestampColumn::encode(&[]);
+		assert!(empty.is_empty());
+		assert_eq!(empty.rows((0, u64::MAX)), 0..0);
+		assert_eq!(empty.decode_all(), Vec::<Timestamp>::new());
+
+		Ok(())
+	})
+}
+
+#[test]
+fn payloads() -> Result<(), anyhow::Error> {
+	tests::run_with_logger(|| {
+		let payloads = vec![b"{}".to_vec(), vec![], b"[1,2]".to_vec(), vec![]];
+		let column = PayloadColumn::encode(&payloads);
+		assert!(column.validate().is_ok());
+		assert_eq!(column.len(), payloads.len());
+		assert_eq!(column.decode_all(), payloads);
+		assert_eq!(column.get(2), b"[1,2]");
+		assert_eq!(column.get(3), b"");
+		// blocks without payloads don't have the column at all
+		assert_eq!(PayloadColumn::default().get(10), b"");
+
+		let data = rmp_serde::to_vec(&column)?;
+		let read: PayloadColumn = rmp_serde::from_slice(&data)?;
+		assert_eq!(read, column);
+
+		let mut broken = column.clone();
+		broken.ends[1] = 100;
+		assert!(broken.validate().is_err());
+		let mut broken = column.clone();
+		broken.data.pop();
+		assert!(broken.validate().is_err());
+
+		Ok(())
+	})
+}
+
+#[test]
+fn broken() -> Result<(), anyhow::Error> {
+	tests::run_with_logger(|| {
+		assert_eq!(try_read_varint(&[0x80, 0x01], 0), Some((128, 2)));
+		assert_eq!(try_read_varint(&[0x80], 0), None);
+		let mut max = Vec::default();
+		write_varint(&mut max, u64::MAX);
+		assert_eq!(try_read_varint(&max, 0), Some((u64::MAX, max.len())));
+		*max.last_mut().unwrap() = 0x02;
+		assert_eq!(try_read_varint(&max, 0), None);
+		assert_eq!(try_read_varint(&[0xff; 11], 0), None);
+
+		let keys = KeyColumn::encode(&urls(100));
+		assert_eq!(keys.try_decode_all()?, urls(100));
+		let mut broken = keys.clone();
+		broken.restarts.pop();
+		assert!(broken.try_decode_all().is_err());
+		let mut broken = keys.clone();
+		broken.restarts[2] += 1;
+		assert!(broken.try_decode_all().is_err());
+		let mut broken = keys.clone();
+		broken.count += 1;
+		assert!(broken.try_decode_all().is_err());
+		let mut broken = keys.clone();
+		broken.data.truncate(keys.data.len() - 1);
+		assert!(broken.try_decode_all().is_err());
+		let mut broken = keys.clone();
+		// the length of the second key
+		broken.data[1 + urls(1)[0].len() + 2] = 0xff;
+		assert!(broken.try_decode_all().is_err());
+
+		let timestamps: Vec<Timestamp> = (0..300).map(|i| 1000 + i * 10).collect();
+		let column = TimestampColumn::encode(&timestamps);
+		assert_eq!(column.try_decode_all()?, timestamps);
+		let mut broken = column.clone();
+		broken.offsets[1] += 1;
+		assert!(broken.try_decode_all().is_err());
+		let mut broken = column.clone();
+		broken.firsts.pop();
+		assert!(broken.try_decode_all().is_err());
+		let mut broken = column.clone();
+		broken.data.pop();
+		assert!(broken.try_decode_all().is_err());
+		let mut broken = column.clone();
+		broken.firsts[2] = u64::MAX;
+		assert!(broken.try_decode_all().is_err());
+
+		Ok(())
+	})
+}
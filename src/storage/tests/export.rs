@@ -0,0 +1,50 @@
+use super::*;
+use crate::tests;
+
+macro_rules! vec_str {
+    ($($x:expr),*) => (vec![$($x.to_string()),*]);
+}
+
+#[test]
+fn formats() -> Result<(), anyhow::Error> {
+	tests::run_with_logger(|| {
+		let records = vec![
+			Record {
+				key: "key0".to_string(),
+				tags: vec_str!["tag0", "tag1"],
+				timestamp: 100,
+				payload: Some(serde_json::json!({"size": 1})),
+			},
+			Record {
+				key: "key1".to_string(),
+				tags: vec![],
+				timestamp: 200,
+				payload: None,
+			},
+		];
+
+		let mut out = Vec::default();
+		for record in records.iter() {
+			write_record(&mut out, record, ExportFormat::Jsonl)?;
+		}
+		// jsonl can be imported back
+		let read: Vec<_> = read_import(&out[..], Format::Jsonl).collect::<Result<_, _>>()?;
+		assert_eq!(read, records);
+
+		let mut out = Vec::default();
+		for record in records.iter() {
+			write_record(&mut out, record, ExportFormat::Msgpack)?;
+		}
+		let mut input = &out[..];
+		let mut read: Vec<Record> = Vec::default();
+		while !input.is_empty() {
+			read.push(rmp_serde::from_read(&mut input)?);
+		}
+		assert_eq!(read, records);
+		// fields are named
+		let value: serde_json::Value = rmp_serde::from_read(&out[..])?;
+		assert_eq!(value["key"], "key0");
+
+		Ok(())
+	})
+}
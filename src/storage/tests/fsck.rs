@@ -0,0 +1,121 @@
+use super::*;
+use crate::tests;
+
+macro_rules! vec_str {
+    ($($x:expr),*) => (vec![$($x.to_string()),*]);
+}
+
+// writes the block with the documents at the timestamps and adds it to the manifest
+fn write_block(dir: &Path, seq: u64, timestamps: &[Timestamp]) -> Result<String, anyhow::Error> {
+	let mut active = ActiveBlock::default();
+	for (i, ts) in timestamps.iter().enumerate() {
+		active.push_at(
+			i as u64,
+			*ts,
+			format!("key{}", ts),
+			vec_str!["tag0", "tag1"],
+		);
+	}
+	let name = BlockName {
+		level: FLUSH_LEVEL,
+		from: timestamps[0],
+		to: *timestamps.last().unwrap(),
+		seq,
+	}
+	.to_string();
+	let file = File::options()
+		.read(true)
+		.write(true)
+		.create_new(true)
+		.open(dir.join(&name))?;
+	active.into_block().write(file).map_err(|(_, err)| err)?;
+	Manifest::open(dir)?.apply(vec![Edit::Add(name.clone())])?;
+	return Ok(name);
+}
+
+#[test]
+fn basic() -> Result<(), anyhow::Error> {
+	tests::run_basic(tests::test_name!(), |dir| {
+		write_block(dir, 0, &[100, 200])?;
+		write_block(dir, 1, &[200, 300])?;
+		write_block(dir, 2, &[400])?;
+		std::fs::write(dir.join("orphan.index"), "orphan")?;
+
+		let report = check_dir(dir)?;
+		assert!(report.is_ok(), "{:?}", report);
+		assert_eq!(report.files.len(), 3);
+		assert_eq!(report.orphans, ["orphan.index"]);
+
+		// nothing to do
+		assert!(quarantine(dir, &report)?.is_empty());
+		assert!(!dir.join(QUARANTINE_DIR).exists());
+
+		Ok(())
+	})
+}
+
+#[test]
+fn corrupt() -> Result<(), anyhow::Error> {
+	tests::run_basic(tests::test_name!(), |dir| {
+		let good = write_block(dir, 0, &[100, 200])?;
+		let truncated = write_block(dir, 1, &[300, 400])?;
+		let overlapping = write_block(dir, 2, &[150, 500])?;
+		let missing = write_block(dir, 3, &[600])?;
+		let garbage = write_block(dir, 4, &[700])?;
+
+		let file = File::options().write(true).open(dir.join(&truncated))?;
+		file.set_len(file.metadata()?.len() - 4)?;
+		std::fs::remove_file(dir.join(&missing))?;
+		let mut data = std::fs::read(dir.join(&garbage))?;
+		let len = data.len();
+		data[len - 20..].fill(0xff);
+		std::fs::write(dir.join(&garbage), data)?;
+
+		let report = check_dir(dir)?;
+		assert!(!report.is_ok());
+		let corrupt: Vec<_> = report.corrupt().map(|file| file.name.clone()).collect();
+		// files are checked in the time order
+		assert_eq!(corrupt, [overlapping, truncated, garbage]);
+		assert!(report.files[1].problems[0].contains("overlaps"));
+		assert!(report.files[2].problems[0].contains("after the end of the file"));
+		assert_eq!(report.missing, [missing]);
+
+		assert_eq!(quarantine(dir, &report)?, corrupt);
+		for name in corrupt.iter() {
+			assert!(!dir.join(name).exists());
+			assert!(dir.join(QUARANTINE_DIR).join(name).exists());
+		}
+		assert_eq!(Manifest::read_files(dir)?.unwrap(), [good]);
+		assert!(check_dir(dir)?.is_ok());
+
+		Ok(())
+	})
+}
+
+#[test]
+fn no_manifest() -> Result<(), anyhow::Error> {
+	tests::async_basic!(dir, {
+		write_block(dir, 0, &[100, 200])?;
+		let garbage = write_block(dir, 1, &[300])?;
+		write_block(dir, 2, &[400, 500])?;
+		std::fs::remove_file(dir.join(MANIFEST_NAME))?;
+		std::fs::write(dir.join(&garbage), "garbage")?;
+
+		let report = check_dir(dir)?;
+		assert_eq!(quarantine(dir, &report)?, [garbage]);
+
+		let config = Config {
+			data_dir: dir.to_path_buf(),
+			..Default::default()
+		};
+		let (storage, stop) = Storage::new(config)?;
+		let matches = storage.query(&Query::new(vec_str!["tag0"], (0, 1000)), 100)?;
+		let mut timestamps: Vec<_> = matches.iter().map(|m| m.timestamp).collect();
+		timestamps.sort();
+		assert_eq!(timestamps, [100, 200, 400, 500]);
+		assert_eq!(std::fs::read_dir(dir.join(QUARANTINE_DIR))?.count(), 1);
+		stop.await?;
+
+		Ok(())
+	})
+}
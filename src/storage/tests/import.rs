@@ -0,0 +1,98 @@
+use super::*;
+use crate::tests;
+
+macro_rules! vec_str {
+    ($($x:expr),*) => (vec![$($x.to_string()),*]);
+}
+
+fn record(key: &str, tags: Vec<String>, timestamp: Timestamp) -> Record {
+	Record {
+		key: key.to_string(),
+		tags,
+		timestamp,
+		payload: None,
+	}
+}
+
+fn read(input: &str, format: Format) -> Result<Vec<Record>, anyhow::Error> {
+	return read_import(input.as_bytes(), format).collect();
+}
+
+#[test]
+fn formats() -> Result<(), anyhow::Error> {
+	tests::run_with_logger(|| {
+		let expected = vec![
+			record("key0", vec_str!["tag0", "tag1"], 200),
+			record("key1", vec![], 100),
+		];
+
+		let jsonl = r#"{"key":"key0","tags":["tag0","tag1"],"timestamp":200}
+
+{"key":"key1","tags":[],"timestamp":100}
+"#;
+		assert_eq!(read(jsonl, Format::Jsonl)?, expected);
+
+		// columns are found by the header
+		let csv = "timestamp,key,tags\n200,key0,tag0 tag1\n100, key1 ,\n";
+		assert_eq!(read(csv, Format::Csv)?, expected);
+
+		let csv = "key,tags,timestamp,payload\nkey0,,1,\"{\"\"a\"\":1}\"\nkey1,,2,\n";
+		let records = read(csv, Format::Csv)?;
+		assert_eq!(records[0].payload, Some(serde_json::json!({"a": 1})));
+		assert_eq!(records[1].payload, None);
+		assert!(read("key,tags,timestamp,payload\nkey0,,1,{\n", Format::Csv).is_err());
+
+		let err = read("{}\n{\"key\"", Format::Jsonl).unwrap_err();
+		assert!(err.to_string().starts_with("line 1:"), "{}", err);
+		assert!(read("key,tags,timestamp\nkey0,tag0,now\n", Format::Csv).is_err());
+		// documents before the broken one are read
+		let mut records = read_import(
+			"{\"key\":\"key0\",\"tags\":[],\"timestamp\":1}\n{".as_bytes(),
+			Format::Jsonl,
+		);
+		assert!(records.next().unwrap().is_ok());
+		assert!(records.next().unwrap().is_err());
+
+		assert_eq!(Format::from_path(Path::new("a.jsonl")), Some(Format::Jsonl));
+		assert_eq!(Format::from_path(Path::new("a.csv")), Some(Format::Csv));
+		assert_eq!(Format::from_path(Path::new("a.txt")), None);
+
+		Ok(())
+	})
+}
+
+#[test]
+fn sorted() -> Result<(), anyhow::Error> {
+	tests::run_basic(tests::test_name!(), |dir| {
+		let records: Vec<_> = (0..1000)
+			.map(|i| record(&format!("key{}", i), vec_str!["tag0"], (i * 7919) % 100))
+			.collect();
+		let size: u64 = records.iter().map(|record| record.size()).sum();
+
+		let sorted = SortedRecords::new(records.iter().cloned().map(Ok), dir, size / 10)?;
+		assert_eq!(sorted.range(), Some((0, 99)));
+		assert_eq!(sorted.total(), 1000);
+		assert!(std::fs::read_dir(dir)?.count() >= 9);
+		let read = sorted.collect::<Result<Vec<_>, _>>()?;
+		// equal timestamps keep the input order
+		let mut expected = records.clone();
+		expected.sort_by_key(|record| record.timestamp);
+		assert_eq!(read, expected);
+		assert_eq!(std::fs::read_dir(dir)?.count(), 0);
+
+		// broken input leaves no runs
+		let broken = records
+			.iter()
+			.cloned()
+			.map(Ok)
+			.chain(std::iter::once(Err(anyhow::anyhow!("broken"))));
+		assert!(SortedRecords::new(broken, dir, size / 10).is_err());
+		assert_eq!(std::fs::read_dir(dir)?.count(), 0);
+
+		let empty = SortedRecords::new(std::iter::empty(), dir, size)?;
+		assert_eq!(empty.range(), None);
+		assert_eq!(empty.total(), 0);
+
+		Ok(())
+	})
+}
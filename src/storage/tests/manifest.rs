@@ -0,0 +1,97 @@
+use super::*;
+use crate::tests;
+
+#[test]
+fn basic() -> Result<(), anyhow::Error> {
+	tests::run_basic(tests::test_name!(), |dir| {
+		let mut manifest = Manifest::open(dir)?;
+		assert!(manifest.files().is_empty());
+
+		manifest.apply(vec![Edit::Add("a".to_string()), Edit::Add("b".to_string())])?;
+		manifest.apply(vec![Edit::Remove("a".to_string())])?;
+		manifest.apply(vec![Edit::Add("c".to_string())])?;
+		assert_eq!(manifest.files(), ["b", "c"]);
+		std::mem::drop(manifest);
+
+		let manifest = Manifest::open(dir)?;
+		assert_eq!(manifest.files(), ["b", "c"]);
+		assert_eq!(manifest.records, 4);
+
+		Ok(())
+	})
+}
+
+#[test]
+fn broken_tail() -> Result<(), anyhow::Error> {
+	tests::run_basic(tests::test_name!(), |dir| {
+		let mut manifest = Manifest::open(dir)?;
+		manifest.apply(vec![Edit::Add("a".to_string()), Edit::Add("b".to_string())])?;
+		std::mem::drop(manifest);
+
+		// crash in the middle of the last record
+		let path = dir.join(MANIFEST_NAME);
+		let len = std::fs::metadata(&path)?.len();
+		File::options().write(true).open(&path)?.set_len(len - 1)?;
+
+		let mut manifest = Manifest::open(dir)?;
+		assert_eq!(manifest.files(), ["a"]);
+		manifest.apply(vec![Edit::Add("c".to_string())])?;
+		std::mem::drop(manifest);
+
+		// broken checksum
+		let mut data = std::fs::read(&path)?;
+		let last = data.len() - 1;
+		data[last] ^= 0xff;
+		std::fs::write(&path, data)?;
+
+		let manifest = Manifest::open(dir)?;
+		assert_eq!(manifest.files(), ["a"]);
+
+		Ok(())
+	})
+}
+
+#[test]
+fn failed_write() -> Result<(), anyhow::Error> {
+	tests::run_basic(tests::test_name!(), |dir| {
+		let mut manifest = Manifest::open(dir)?;
+		manifest.apply(vec![Edit::Add("a".to_string())])?;
+
+		// neither write, nor cut back work with the read only file
+		manifest.file = File::open(dir.join(MANIFEST_NAME))?;
+		assert!(manifest.apply(vec![Edit::Add("b".to_string())]).is_err());
+		assert_eq!(manifest.files(), ["a"]);
+		assert!(manifest.failed);
+		manifest.file = File::options()
+			.append(true)
+			.read(true)
+			.open(dir.join(MANIFEST_NAME))?;
+		assert!(manifest.apply(vec![Edit::Add("c".to_string())]).is_err());
+		std::mem::drop(manifest);
+
+		let manifest = Manifest::open(dir)?;
+		assert_eq!(manifest.files(), ["a"]);
+
+		Ok(())
+	})
+}
+
+#[test]
+fn rewrite() -> Result<(), anyhow::Error> {
+	tests::run_basic(tests::test_name!(), |dir| {
+		let mut manifest = Manifest::open(dir)?;
+		for i in 0..REWRITE_RECORDS {
+			manifest.apply(vec![Edit::Add(format!("{}", i))])?;
+			manifest.apply(vec![Edit::Remove(format!("{}", i))])?;
+		}
+		manifest.apply(vec![Edit::Add("last".to_string())])?;
+		assert!(manifest.records <= REWRITE_RECORDS);
+		std::mem::drop(manifest);
+
+		let manifest = Manifest::open(dir)?;
+		assert_eq!(manifest.files(), ["last"]);
+		assert!(!dir.join(MANIFEST_NAME).with_extension("tmp").exists());
+
+		Ok(())
+	})
+}
@@ -0,0 +1,39 @@
+use super::*;
+use crate::tests;
+
+#[test]
+fn render() -> Result<(), anyhow::Error> {
+	tests::run_with_logger(|| {
+		let metrics = Metrics::default();
+		metrics.documents.add(3);
+		metrics.query_duration.observe(Duration::from_micros(700));
+		metrics.query_duration.observe(Duration::from_millis(30));
+		metrics.query_duration.observe(Duration::from_secs(20));
+		let gauges = Gauges {
+			file_blocks: 2,
+			..Default::default()
+		};
+
+		let out = metrics.render(&gauges);
+		let lines: Vec<_> = out.lines().collect();
+		for line in [
+			"# TYPE tagged_documents_total counter",
+			"tagged_documents_total 3",
+			"tagged_blocks{type=\"file\"} 2",
+			"tagged_compaction_duration_seconds_bucket{le=\"+Inf\"} 0",
+			"tagged_compaction_duration_seconds_count 0",
+			"tagged_request_duration_seconds_bucket{op=\"query\",le=\"0.0005\"} 0",
+			"tagged_request_duration_seconds_bucket{op=\"query\",le=\"0.001\"} 1",
+			"tagged_request_duration_seconds_bucket{op=\"query\",le=\"0.05\"} 2",
+			"tagged_request_duration_seconds_bucket{op=\"query\",le=\"10\"} 2",
+			"tagged_request_duration_seconds_bucket{op=\"query\",le=\"+Inf\"} 3",
+			"tagged_request_duration_seconds_sum{op=\"query\"} 20.0307",
+			"tagged_request_duration_seconds_count{op=\"query\"} 3",
+			"tagged_request_duration_seconds_count{op=\"list_tags\"} 0",
+		] {
+			assert!(lines.contains(&line), "{} is missing in\n{}", line, out);
+		}
+
+		Ok(())
+	})
+}
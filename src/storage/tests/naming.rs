@@ -0,0 +1,85 @@
+use super::*;
+use crate::tests;
+
+#[test]
+fn basic() -> Result<(), anyhow::Error> {
+	tests::run_with_logger(|| {
+		let name = BlockName {
+			level: FLUSH_LEVEL,
+			from: 1635000000000,
+			to: 1635000001000,
+			seq: 7,
+		};
+		let s = name.to_string();
+		assert_eq!(
+			s,
+			"0000000000-00000001635000000000-00000001635000001000-00000000000000000007.index"
+		);
+		assert_eq!(BlockName::parse(&s), Some(name));
+
+		let max = BlockName {
+			level: u32::MAX,
+			from: u64::MAX,
+			to: u64::MAX,
+			seq: u64::MAX,
+		};
+		assert_eq!(BlockName::parse(&max.to_string()), Some(max));
+
+		assert_eq!(BlockName::parse("00-1-2-3"), None);
+		assert_eq!(BlockName::parse("00-1-2.index"), None);
+		assert_eq!(BlockName::parse("00-1-2-3-4.index"), None);
+		assert_eq!(BlockName::parse("00-a-2-3.index"), None);
+		assert_eq!(
+			BlockName::parse("c9a646d3-9c61-11ec-8000-000000000000.index"),
+			None
+		);
+
+		Ok(())
+	})
+}
+
+#[test]
+fn order() -> Result<(), anyhow::Error> {
+	tests::run_with_logger(|| {
+		let name = |level, from, seq| BlockName {
+			level,
+			from,
+			to: from + 10,
+			seq,
+		};
+		let names = vec![
+			name(0, 5, 3),
+			name(0, 100, 1),
+			name(0, 100, 2),
+			name(0, 1000, 0),
+			name(1, 0, 4),
+			name(2, 0, 5),
+			name(100, 0, 6),
+		];
+		let mut strings: Vec<String> = names.iter().rev().map(|x| x.to_string()).collect();
+		strings.sort();
+		let parsed: Vec<_> = strings
+			.iter()
+			.map(|x| BlockName::parse(x).unwrap())
+			.collect();
+		assert_eq!(parsed, names);
+
+		// older names with the two digit level are sorted together with the new ones
+		let mut strings = vec![
+			"0000000000-00000000000000000200-00000000000000000300-00000000000000000002.index"
+				.to_string(),
+			"00-00000000000000000300-00000000000000000400-00000000000000000001.index".to_string(),
+			"0000000000-00000000000000000100-00000000000000000200-00000000000000000003.index"
+				.to_string(),
+			"unknown.index".to_string(),
+		];
+		sort_names(&mut strings);
+		let from: Vec<_> = strings
+			.iter()
+			.map(|x| BlockName::parse(x).map(|name| name.from))
+			.collect();
+		assert_eq!(from, [None, Some(100), Some(200), Some(300)]);
+
+		Ok(())
+	})
+}
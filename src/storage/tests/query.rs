@@ -0,0 +1,116 @@
+use super::*;
+use crate::tests;
+use std::io::Cursor;
+
+macro_rules! vec_str {
+	($($x:expr),*) => (vec![$($x.to_string()),*]);
+}
+
+fn file_block() -> Result<BlockFile<Cursor<Vec<u8>>>, anyhow::Error> {
+	let mut active = ActiveBlock::default();
+	active.push("key0".to_string(), vec_str!["big", "mid"]);
+	active.push("key1".to_string(), vec_str!["big", "mid", "small"]);
+	active.push("key2".to_string(), vec_str!["big"]);
+	active.push("key3".to_string(), vec_str!["big", "mid", "other"]);
+	active.push("key4".to_string(), vec_str!["big", "small"]);
+	let block = active
+		.into_block()
+		.write(Cursor::new(Vec::default()))
+		.map_err(|(_, err)| err)?;
+	let (mut file, _, _) = block.release_all();
+	return Ok(BlockHeader::read_header(&mut file, 0)?.open(file));
+}
+
+#[test]
+fn intersect() -> Result<(), anyhow::Error> {
+	tests::run_with_logger(|| {
+		assert_eq!(
+			super::intersect(&[1, 3, 5, 7], &[2, 3, 4, 7, 9]),
+			vec![3, 7]
+		);
+		assert_eq!(super::intersect(&[], &[1, 2]), Vec::<Index>::new());
+
+		let large: Vec<Index> = (0..1000).map(|x| x * 2).collect();
+		assert_eq!(super::intersect(&[3, 4, 500, 1999], &large), vec![4, 500]);
+		assert_eq!(super::intersect(&large, &[0, 1998, 1999]), vec![0, 1998]);
+
+		Ok(())
+	})
+}
+
+#[test]
+fn plan() -> Result<(), anyhow::Error> {
+	tests::run_with_logger(|| {
+		let mut block = file_block()?;
+		block.read_column(Column::Tags)?;
+		let all = (MIN_TIME, MAX_TIME);
+		let tags = block.get_tags().to_vec();
+		let id = |tag: &str| tags.iter().position(|x| x == tag).unwrap();
+
+		let query = Query::new(vec_str!["big", "mid", "small", "mid"], all);
+		assert_eq!(
+			query.plan(&block),
+			Some(vec![id("small"), id("mid"), id("big")])
+		);
+
+		let query = Query::new(vec_str!["big", "unknown"], all);
+		assert_eq!(query.plan(&block), None);
+
+		let query = Query::new(vec_str!["big"], (0, 1));
+		assert_eq!(query.plan(&block), None);
+
+		Ok(())
+	})
+}
+
+#[test]
+fn execute() -> Result<(), anyhow::Error> {
+	tests::run_with_logger(|| {
+		let mut block = file_block()?;
+		let all = (MIN_TIME, MAX_TIME);
+		block.read_column(Column::Tags)?;
+		let tags = block.get_tags().to_vec();
+		block.release_column(Column::Tags);
+		let id = |tag: &str| tags.iter().position(|x| x == tag).unwrap();
+
+		// bloom filter doesn't let us load the tags
+		let query = Query::new(vec_str!["unknown"], all);
+		let other: Arc<RwLock<dyn SearchBlock>> = Arc::new(RwLock::new(file_block()?));
+		assert_eq!(query.execute(&other)?, Vec::<Index>::new());
+		assert!(!other.read().unwrap().is_loaded(Column::Tags));
+		let block: Arc<RwLock<dyn SearchBlock>> = Arc::new(RwLock::new(block));
+
+		// "other" and "small" have nothing in common, so "big" and "mid" shouldn't be read
+		let query = Query::new(vec_str!["big", "other", "mid", "small"], all);
+		assert_eq!(query.execute(&block)?, Vec::<Index>::new());
+		{
+			let block = block.read().unwrap();
+			assert!(block.try_get_index(id("other")).is_some());
+			assert!(block.try_get_index(id("small")).is_some());
+			assert!(block.try_get_index(id("big")).is_none());
+			assert!(block.try_get_index(id("mid")).is_none());
+		}
+
+		let query = Query::new(vec_str!["big", "mid"], all);
+		assert_eq!(query.execute(&block)?, vec![0, 1, 3]);
+		// the block is inside the range, so the timestamps aren't read
+		assert!(!block.read().unwrap().is_loaded(Column::Timestamps));
+
+		let query = Query::new(vec_str![], all);
+		assert_eq!(query.execute(&block)?, vec![0, 1, 2, 3, 4]);
+		assert!(!block.read().unwrap().is_loaded(Column::Timestamps));
+
+		// the range bounds are inside the block
+		let mut active = ActiveBlock::default();
+		for i in 0..5 {
+			active.push_at(i, 100 + i * 100, format!("key{}", i), vec_str!["big"]);
+		}
+		let block: Arc<RwLock<dyn SearchBlock>> = Arc::new(RwLock::new(active.into_block()));
+		let query = Query::new(vec_str!["big"], (200, 400));
+		assert_eq!(query.execute(&block)?, vec![1, 2, 3]);
+		let query = Query::new(vec_str![], (150, 1000));
+		assert_eq!(query.execute(&block)?, vec![1, 2, 3, 4]);
+
+		Ok(())
+	})
+}
@@ -22,6 +22,7 @@ fn new_doc(key: &str, tags: Vec<String>) -> Document {
 	Document {
 		key: key.to_string(),
 		tags,
+		payload: None,
 	}
 }
 
@@ -55,27 +56,28 @@ fn random_data(size: usize) -> Vec<Document> {
 		tags: gen_vec(thread_rng().gen_range(1..20), |i| {
 			format!("tag_name_{}_{}", thread_rng().gen_range(1..20), i)
 		}),
+		payload: None,
 	})
 }
 
 fn from_block(block: &dyn SearchBlock) -> Vec<Document> {
-	let mut map: BTreeMap<String, Vec<String>> = BTreeMap::default();
+	// documents are in the push order, keys can repeat
+	let mut map: BTreeMap<usize, Vec<String>> = BTreeMap::default();
 	let tags = block.get_tags();
-	let keys = block.get_keys();
 	for (j, tag) in tags.iter().enumerate() {
-		let tag_keys: Option<Vec<&str>> = block
-			.try_get_index(j)
-			.map(|v| v.iter().map(|i| -> &str { &keys[(*i) as usize] }).collect());
-
-		if let Some(tag_keys) = tag_keys {
-			for key in tag_keys {
-				map.entry(key.to_string()).or_default().push(tag.clone());
+		if let Some(ids) = block.try_get_index(j) {
+			for id in ids.iter() {
+				map.entry(*id as usize).or_default().push(tag.clone());
 			}
 		}
 	}
 	return map
 		.into_iter()
-		.map(|(key, tags)| Document { key, tags })
+		.map(|(id, tags)| Document {
+			key: block.get_key(id),
+			tags,
+			payload: block.get_payload(id),
+		})
 		.collect();
 }
 
@@ -84,6 +86,9 @@ fn read_all(iter: StorageIter) -> (Vec<Document>, String) {
 	let mut debug_output = String::new();
 
 	for (i, block) in iter.enumerate() {
+		let tags = read_columns(&block, &COLUMNS).unwrap().get_tags().len();
+		let ids: Vec<usize> = (0..tags).collect();
+		let _: Vec<_> = read_indexes(Arc::clone(&block), &ids).unwrap().collect();
 		let block = block.read().unwrap();
 		let data = from_block(&*block);
 		debug_output += &format!("block={} type={:?}\n", i, block.get_type());
@@ -97,6 +102,13 @@ fn read_all(iter: StorageIter) -> (Vec<Document>, String) {
 	return (storage_data, debug_output);
 }
 
+// the save worker writes blocks in the background, so wait for the result
+async fn wait_for(condition: impl Fn() -> bool) {
+	while !condition() {
+		tokio::time::sleep(std::time::Duration::from_millis(1)).await;
+	}
+}
+
 fn check_storage(storage: &Storage, data: &[Document]) {
 	let (storage_data, debug_output) = read_all(storage.iter());
 	if storage_data != data {
@@ -110,10 +122,11 @@ fn basic() -> Result<(), anyhow::Error> {
 	tests::async_basic!(data_dir, {
 		let config = Config {
 			data_dir: data_dir.to_path_buf(),
-			max_active_size: 3,
-			max_block_size: 10,
+			max_active_size: 64,
+			max_block_size: 256,
+			..Default::default()
 		};
-		let (storage, stop) = Storage::new(config, Arc::new(uuid::v1::Context::new(0)))?;
+		let (storage, stop) = Storage::new(config)?;
 		let mut data = simple_data();
 
 		tokio::task::yield_now().await;
@@ -137,43 +150,870 @@ fn basic() -> Result<(), anyhow::Error> {
 }
 
 #[test]
-fn bench() -> Result<(), anyhow::Error> {
+fn list_tags() -> Result<(), anyhow::Error> {
 	tests::async_basic!(data_dir, {
 		let config = Config {
 			data_dir: data_dir.to_path_buf(),
-			max_active_size: 800,
-			max_block_size: 100 * 800,
+			max_active_size: 64,
+			max_block_size: 256,
+			..Default::default()
 		};
-		let (storage, stop) = Storage::new(config, Arc::new(uuid::v1::Context::new(0)))?;
-		const BATCH_SIZE: usize = 1000;
-		const BATCHES_SIZE: usize = 100;
-		let batches = gen_vec(BATCHES_SIZE, |_| random_data(BATCH_SIZE));
+		let (storage, stop) = Storage::new(config)?;
+		let data = simple_data();
+		let all = (MIN_TIME, MAX_TIME);
+
+		tokio::task::yield_now().await;
+		for doc in data {
+			storage.push(doc.key, doc.tags).await?;
+			tokio::task::yield_now().await;
+		}
+
+		let tags: Vec<_> = (0..9).map(|i| format!("tag{}", i)).collect();
+		assert_eq!(storage.list_tags("", None, all, 100)?, tags);
+		assert_eq!(storage.list_tags("tag", None, all, 100)?, tags);
+		assert_eq!(storage.list_tags("tag", None, all, 3)?, tags[0..3]);
+		assert_eq!(storage.list_tags("tag", Some("tag2"), all, 3)?, tags[3..6]);
+		assert_eq!(
+			storage.list_tags("tag", Some("tag8"), all, 3)?,
+			Vec::<String>::new()
+		);
+		assert_eq!(storage.list_tags("tag1", None, all, 100)?, vec_str!["tag1"]);
+		assert_eq!(
+			storage.list_tags("key", None, all, 100)?,
+			Vec::<String>::new()
+		);
+		assert_eq!(
+			storage.list_tags("", None, (0, 1), 100)?,
+			Vec::<String>::new()
+		);
+
+		stop.await?;
+
+		Ok(())
+	})
+}
+
+#[test]
+fn query() -> Result<(), anyhow::Error> {
+	tests::async_basic!(data_dir, {
+		let config = Config {
+			data_dir: data_dir.to_path_buf(),
+			max_active_size: 64,
+			max_block_size: 256,
+			..Default::default()
+		};
+		let (storage, stop) = Storage::new(config)?;
+		let data = simple_data();
+		let all = (MIN_TIME, MAX_TIME);
+
+		tokio::task::yield_now().await;
+		for doc in data.iter() {
+			storage.push(doc.key.clone(), doc.tags.clone()).await?;
+			tokio::task::yield_now().await;
+		}
+
+		let keys = |query: &Query, limit: usize| -> Result<Vec<String>, anyhow::Error> {
+			Ok(storage
+				.query(query, limit)?
+				.into_iter()
+				.map(|m| m.key)
+				.collect())
+		};
+
+		let query = Query::new(vec_str!["tag0", "tag3"], all);
+		assert_eq!(keys(&query, 100)?, vec_str!["key07", "key06", "key02"]);
+		assert_eq!(keys(&query, 2)?, vec_str!["key07", "key06"]);
+
+		let query = Query::new(vec_str!["tag2"], all);
+		let expected: Vec<String> = data
+			.iter()
+			.rev()
+			.filter(|doc| doc.tags.contains(&"tag2".to_string()))
+			.map(|doc| doc.key.clone())
+			.collect();
+		assert_eq!(keys(&query, 100)?, expected);
+
+		let query = Query::new(vec_str!["tag7", "tag8"], all);
+		assert_eq!(keys(&query, 100)?, Vec::<String>::new());
+
+		let query = Query::new(vec_str!["tag0"], (0, 1));
+		assert_eq!(keys(&query, 100)?, Vec::<String>::new());
+
+		let matches = storage.query(&Query::new(vec_str![], all), 100)?;
+		assert_eq!(matches.len(), data.len());
+		assert!(matches.windows(2).all(|w| w[0].timestamp >= w[1].timestamp));
+
+		stop.await?;
+
+		Ok(())
+	})
+}
+
+#[test]
+fn query_shards() -> Result<(), anyhow::Error> {
+	tests::async_basic!(data_dir, {
+		let config = Config {
+			data_dir: data_dir.to_path_buf(),
+			active_shards: 4,
+			..Default::default()
+		};
+		let (storage, stop) = Storage::new(config)?;
+		let data = simple_data();
+		// pushes go to the shards round-robin, usually in the same millisecond
+		for doc in data.iter() {
+			storage.push(doc.key.clone(), doc.tags.clone()).await?;
+		}
+		assert!(storage.block_list().sealed.is_empty());
+
+		let query = Query::new(vec_str![], (MIN_TIME, MAX_TIME));
+		let all: Vec<String> = data.iter().rev().map(|doc| doc.key.clone()).collect();
+		for limit in 1..=data.len() {
+			let keys: Vec<String> = storage
+				.query(&query, limit)?
+				.into_iter()
+				.map(|m| m.key)
+				.collect();
+			assert_eq!(keys, all[..limit]);
+		}
+		let query = Query::new(vec_str!["tag0"], (MIN_TIME, MAX_TIME));
+		let keys: Vec<String> = storage
+			.query(&query, 2)?
+			.into_iter()
+			.map(|m| m.key)
+			.collect();
+		assert_eq!(keys, vec_str!["key08", "key07"]);
+
+		let mut exported = Vec::default();
+		storage.export(&query, |record| {
+			exported.push(record.key);
+			Ok(())
+		})?;
+		assert_eq!(
+			exported,
+			vec_str!["key00", "key02", "key03", "key06", "key07", "key08"]
+		);
+
+		stop.await?;
+
+		Ok(())
+	})
+}
+
+#[test]
+fn block_list() -> Result<(), anyhow::Error> {
+	tests::async_basic!(data_dir, {
+		let config = Config {
+			data_dir: data_dir.to_path_buf(),
+			max_active_size: 64,
+			max_block_size: 256,
+			..Default::default()
+		};
+		let (storage, stop) = Storage::new(config)?;
+		let mut data = simple_data();
+		for doc in data.iter_mut() {
+			doc.tags.sort();
+		}
+		let half = data.len() / 2;
+
+		tokio::task::yield_now().await;
+		for doc in data[..half].iter() {
+			storage.push(doc.key.clone(), doc.tags.clone()).await?;
+			tokio::task::yield_now().await;
+		}
+
+		// the old version is kept by the iterator, while the save worker
+		// seals, compacts and writes the new blocks
+		let iter = storage.iter();
+		let version = storage.block_list().version;
+		for doc in data[half..].iter() {
+			storage.push(doc.key.clone(), doc.tags.clone()).await?;
+			tokio::task::yield_now().await;
+		}
+		wait_for(|| !storage.block_list().files.is_empty()).await;
+		assert!(storage.block_list().version > version);
+
+		assert_eq!(read_all(iter).0, data[..half]);
+		check_storage(&storage, &data);
+
+		stop.await?;
+
+		Ok(())
+	})
+}
+
+#[test]
+fn parallel_writes() -> Result<(), anyhow::Error> {
+	tests::async_basic!(data_dir, {
+		let config = Config {
+			data_dir: data_dir.to_path_buf(),
+			max_active_size: 64,
+			max_block_size: 256,
+			save_workers: 4,
+			..Default::default()
+		};
+		let (storage, stop) = Storage::new(config)?;
+		let mut data = Vec::default();
+
+		tokio::task::yield_now().await;
+		for _ in 0..8 {
+			for mut doc in simple_data() {
+				storage.push(doc.key.clone(), doc.tags.clone()).await?;
+				doc.tags.sort();
+				data.push(doc);
+				tokio::task::yield_now().await;
+			}
+		}
+
+		wait_for(|| storage.block_list().files.len() > 4).await;
+		let files = storage.block_list().files.clone();
+		assert!(files
+			.windows(2)
+			.all(|w| w[0].read().unwrap().range().1 <= w[1].read().unwrap().range().0));
+		check_storage(&storage, &data);
+
+		stop.await?;
+
+		// blocks are renamed only after they are written, so no temporary files are left
+		let mut names = Vec::default();
+		for entry in std::fs::read_dir(data_dir)? {
+			names.push(entry?.path());
+		}
+		names.retain(|name| !name.ends_with(MANIFEST_NAME) && !name.ends_with(LOCK_NAME));
+		assert_eq!(names.len(), storage.block_list().files.len());
+		assert!(names
+			.iter()
+			.all(|name| name.extension().unwrap() == "index"));
+
+		// blocks are ordered by the file names alone
+		names.sort();
+		let names: Vec<_> = names
+			.iter()
+			.map(|name| BlockName::parse(&file_name(name)).unwrap())
+			.collect();
+		assert!(names.windows(2).all(|w| w[0].to <= w[1].from));
+
+		Ok(())
+	})
+}
+
+#[test]
+fn lock() -> Result<(), anyhow::Error> {
+	tests::async_basic!(data_dir, {
+		let config = Config {
+			data_dir: data_dir.to_path_buf(),
+			..Default::default()
+		};
+		let (storage, stop) = Storage::new(config.clone())?;
+		let err = Storage::new(config.clone()).err().unwrap();
+		assert!(
+			err.to_string().contains("used by another process"),
+			"{}",
+			err
+		);
+		assert!(lock_dir(data_dir).is_err());
+		stop.await?;
+
+		// the stopped storage can still be referenced
+		let (_, stop) = Storage::new(config)?;
+		assert_eq!(storage.block_list().files.len(), 0);
+		stop.await?;
+
+		Ok(())
+	})
+}
+
+#[test]
+fn reopen() -> Result<(), anyhow::Error> {
+	tests::async_basic!(data_dir, {
+		let config = Config {
+			data_dir: data_dir.to_path_buf(),
+			max_active_size: 64,
+			max_block_size: 256,
+			..Default::default()
+		};
+		let (storage, stop) = Storage::new(config.clone())?;
+
+		tokio::task::yield_now().await;
+		for _ in 0..4 {
+			for doc in simple_data() {
+				storage.push(doc.key, doc.tags).await?;
+				tokio::task::yield_now().await;
+			}
+		}
+		wait_for(|| storage.block_list().files.len() > 2).await;
+		stop.await?;
+
+		// only the files are persisted
+		let files_len = storage.block_list().files.len();
+		let files = BlockList {
+			files: storage.block_list().files.clone(),
+			..Default::default()
+		};
+		let expected = read_all(StorageIter::new(vec![], Arc::new(files))).0;
+
+		let orphans = [data_dir.join("orphan.index"), data_dir.join("orphan.tmp")];
+		for orphan in orphans.iter() {
+			std::fs::write(orphan, "orphan")?;
+		}
+
+		let (storage, stop) = Storage::new(config.clone())?;
+		assert!(orphans.iter().all(|orphan| !orphan.exists()));
+		// block files can be lost by the broken manifest, so they're kept
+		assert!(data_dir.join(QUARANTINE_DIR).join("orphan.index").exists());
+		assert_eq!(read_all(storage.iter()).0, expected);
+		stop.await?;
+
+		// data dir from before the manifest
+		std::fs::remove_file(data_dir.join(MANIFEST_NAME))?;
+		let (storage, stop) = Storage::new(config)?;
+		assert_eq!(read_all(storage.iter()).0, expected);
+		stop.await?;
+		assert_eq!(Manifest::read_files(data_dir)?.unwrap().len(), files_len);
+
+		Ok(())
+	})
+}
+
+#[test]
+fn unload() -> Result<(), anyhow::Error> {
+	tests::async_basic!(data_dir, {
+		let config = Config {
+			data_dir: data_dir.to_path_buf(),
+			max_active_size: 64,
+			max_block_size: 256,
+			max_loaded_blocks: 2,
+			..Default::default()
+		};
+		let (storage, stop) = Storage::new(config)?;
+
+		tokio::task::yield_now().await;
+		for _ in 0..4 {
+			for doc in simple_data() {
+				storage.push(doc.key, doc.tags).await?;
+				tokio::task::yield_now().await;
+			}
+		}
+
+		let loaded = || {
+			storage
+				.block_list()
+				.files
+				.iter()
+				.filter(|block| block.read().unwrap().is_loaded(Column::Tags))
+				.count()
+		};
+		wait_for(|| storage.block_list().files.len() > 2).await;
+
+		storage.list_tags("", None, (MIN_TIME, MAX_TIME), 100)?;
+		assert_eq!(loaded(), 2);
+
+		let query = Query::new(vec_str!["tag0"], (MIN_TIME, MAX_TIME));
+		assert_eq!(storage.query(&query, 1000)?.len(), 4 * 6);
+		assert_eq!(loaded(), 2);
+
+		stop.await?;
+
+		Ok(())
+	})
+}
+
+#[test]
+fn backpressure() -> Result<(), anyhow::Error> {
+	tests::async_basic!(data_dir, {
+		let config = Config {
+			data_dir: data_dir.to_path_buf(),
+			// 36 bytes for the first document in the block and 28 for the second
+			max_active_size: 64,
+			max_block_size: 1024,
+			max_pending_blocks: 2,
+			reject_on_backpressure: true,
+			..Default::default()
+		};
+		let (storage, stop) = Storage::new(config)?;
+		tokio::task::yield_now().await;
+
+		// save worker can't run until we yield, so sealed blocks pile up
+		let mut pushed = 0;
+		let err = loop {
+			let key = format!("key{}", pushed);
+			match storage.push(key, vec_str!["tag0", "tag1"]).await {
+				Ok(()) => pushed += 1,
+				Err(err) => break err,
+			}
+		};
+		assert!(err.is::<Overloaded>());
+		// two sealed blocks and the full active one
+		assert_eq!(pushed, 6);
+		assert_eq!(storage.block_list().sealed.len(), 2);
+
+		while !storage.block_list().sealed.is_empty() {
+			tokio::task::yield_now().await;
+		}
+		storage.push("key".to_string(), vec_str!["tag0"]).await?;
+
+		stop.await?;
+
+		Ok(())
+	})
+}
+
+#[test]
+fn wait_for_active() -> Result<(), anyhow::Error> {
+	tests::async_basic!(data_dir, {
+		let config = Config {
+			data_dir: data_dir.to_path_buf(),
+			max_active_size: 64,
+			max_block_size: 256,
+			max_pending_blocks: 1,
+			..Default::default()
+		};
+		let (storage, stop) = Storage::new(config)?;
+		tokio::task::yield_now().await;
+
+		// writers wait for the save worker instead of failing
+		let data = simple_data();
+		for _ in 0..10 {
+			storage.push_batch(data.clone()).await?;
+		}
+		let all = Query::new(vec_str![], (MIN_TIME, MAX_TIME));
+		assert_eq!(storage.query(&all, 1000)?.len(), data.len() * 10);
+
+		stop.await?;
+
+		Ok(())
+	})
+}
+
+#[test]
+fn max_age() -> Result<(), anyhow::Error> {
+	tests::async_basic!(data_dir, {
+		let config = Config {
+			data_dir: data_dir.to_path_buf(),
+			max_active_age: Duration::from_millis(20),
+			max_block_age: Duration::from_millis(50),
+			..Default::default()
+		};
+		let (storage, stop) = Storage::new(config)?;
+		tokio::task::yield_now().await;
+
+		// documents are far below the size limits, but they are persisted anyway
+		let mut data = simple_data();
+		storage.push_batch(data.clone()).await?;
+		assert_eq!(storage.block_list().sealed.len(), 0);
+		data.iter_mut().for_each(|doc| doc.tags.sort());
+
+		wait_for(|| storage.block_list().files.len() == 1).await;
+		assert!(storage.block_list().in_memory.is_empty());
+		check_storage(&storage, &data);
+
+		stop.await?;
+
+		Ok(())
+	})
+}
 
+#[test]
+fn metrics() -> Result<(), anyhow::Error> {
+	tests::async_basic!(data_dir, {
+		let config = Config {
+			data_dir: data_dir.to_path_buf(),
+			max_active_size: 64,
+			max_block_size: 256,
+			..Default::default()
+		};
+		let (storage, stop) = Storage::new(config)?;
 		tokio::task::yield_now().await;
 
-		log::debug!("started");
-		let start = Instant::now();
-		let mut join = Vec::with_capacity(batches.len());
-		for batch in batches {
-			//tokio::task::yield_now().await;
-			let storage = Arc::clone(&storage);
-			join.push(tokio::task::spawn(async move {
-				storage.push_batch(batch).await
-			}));
+		let data = simple_data();
+		for _ in 0..4 {
+			storage.push_batch(data.clone()).await?;
 		}
+		wait_for(|| storage.block_list().files.len() > 1).await;
+		let all = Query::new(vec_str!["tag0"], (MIN_TIME, MAX_TIME));
+		storage.query(&all, 1000)?;
+
+		let metrics = storage.metrics();
+		let value = |name: &str| -> u64 {
+			let line = metrics
+				.lines()
+				.find(|line| line.starts_with(&format!("{} ", name)))
+				.unwrap();
+			line[name.len() + 1..].parse().unwrap()
+		};
+		assert_eq!(value("tagged_documents_total"), data.len() as u64 * 4);
+		assert!(value("tagged_block_writes_total") > 1);
+		assert_eq!(value("tagged_block_write_failures_total"), 0);
+		assert!(value("tagged_loaded_blocks") > 1);
+		assert!(value("tagged_loaded_index_bytes") > 0);
+		assert!(metrics.contains("\ntagged_blocks{type=\"file\"} "));
+
+		stop.await?;
+
+		Ok(())
+	})
+}
+
+#[test]
+fn stats() -> Result<(), anyhow::Error> {
+	tests::async_basic!(data_dir, {
+		let config = Config {
+			data_dir: data_dir.to_path_buf(),
+			max_active_size: 64,
+			max_block_size: 256,
+			..Default::default()
+		};
+		let (storage, stop) = Storage::new(config)?;
+		tokio::task::yield_now().await;
 
-		let err: Result<Vec<_>, _> = futures::future::join_all(join).await.into_iter().collect();
-		err?;
+		let data = simple_data();
+		for _ in 0..4 {
+			storage.push_batch(data.clone()).await?;
+		}
+		wait_for(|| !storage.block_list().files.is_empty()).await;
+		// small enough to stay in the active block
+		storage.push("key".to_string(), vec_str!["tag"]).await?;
 
-		let took = Instant::now() - start;
-		log::debug!(
-			"took {:.2?}; {}op/s",
-			took,
-			BATCH_SIZE * BATCHES_SIZE * 1000000000 / took.as_nanos() as usize
+		let stats = storage.stats();
+		assert_eq!(stats.total.documents, data.len() as u64 * 4 + 1);
+		assert_eq!(stats.total.blocks, stats.blocks.len());
+		assert_eq!(stats.blocks[0].block_type, BlockType::Active);
+		assert_eq!(stats.blocks[0].documents, 1);
+		assert_eq!(stats.active.documents, 1);
+		assert!(stats.files.blocks > 0);
+		assert!(stats.files.disk_size > 0);
+		assert_eq!(stats.in_memory.disk_size, 0);
+		assert_eq!(
+			stats.total.documents,
+			stats.active.documents + stats.in_memory.documents + stats.files.documents
 		);
+		// every block is older than the one before
+		assert!(stats
+			.blocks
+			.windows(2)
+			.all(|w| w[1].range.unwrap().1 <= w[0].range.unwrap().0));
 
 		stop.await?;
 
 		Ok(())
 	})
 }
+
+#[test]
+fn import() -> Result<(), anyhow::Error> {
+	tests::async_basic!(data_dir, {
+		let config = Config {
+			data_dir: data_dir.to_path_buf(),
+			max_active_size: 64,
+			max_block_size: 256,
+			..Default::default()
+		};
+		let (storage, stop) = Storage::new(config.clone())?;
+		tokio::task::yield_now().await;
+
+		let pushed = simple_data();
+		storage.push_batch(pushed[..2].to_vec()).await?;
+
+		let mut imported = simple_data();
+		let mut records: Vec<_> = imported
+			.iter()
+			.enumerate()
+			.map(|(i, doc)| Record {
+				key: doc.key.clone(),
+				tags: doc.tags.clone(),
+				timestamp: 1000 + i as Timestamp,
+				payload: None,
+			})
+			.collect();
+		// input order doesn't matter
+		records.reverse();
+		assert!(storage.import(records.into_iter().map(Ok))? > 1);
+		for doc in imported.iter_mut() {
+			doc.tags.sort();
+		}
+
+		// newer than the pushed documents
+		let newer = Record {
+			key: "newer".to_string(),
+			tags: vec_str!["tag0"],
+			timestamp: current_time() + 3_600_000,
+			payload: None,
+		};
+		let files = storage.block_list().files.len();
+		let clock = *storage.clock.lock().unwrap();
+		assert!(storage.import([Ok(newer)]).is_err());
+		assert_eq!(storage.block_list().files.len(), files);
+		assert_eq!(*storage.clock.lock().unwrap(), clock);
+
+		let older = Record {
+			key: "older".to_string(),
+			tags: vec_str!["tag0", "tag0"],
+			timestamp: 1000,
+			payload: None,
+		};
+		assert_eq!(storage.import([Ok(older)])?, 1);
+		// no sorted runs are left
+		assert!(std::fs::read_dir(data_dir)?.all(|entry| !entry
+			.unwrap()
+			.path()
+			.to_string_lossy()
+			.ends_with(".tmp")));
+		imported.insert(0, new_doc("older", vec_str!["tag0"]));
+
+		let mut expected = imported.clone();
+		expected.extend(pushed[..2].iter().map(|doc| {
+			let mut doc = doc.clone();
+			doc.tags.sort();
+			doc
+		}));
+		check_storage(&storage, &expected);
+		let first = storage.block_list().files[0].read().unwrap().range();
+		assert_eq!(first, (1000, 1000));
+		stop.await?;
+
+		// imported files are the oldest after reopen too
+		let (storage, stop) = Storage::new(config)?;
+		let (data, _) = read_all(storage.iter());
+		assert_eq!(data[..imported.len()], imported[..]);
+		stop.await?;
+
+		Ok(())
+	})
+}
+
+#[test]
+fn export() -> Result<(), anyhow::Error> {
+	tests::async_basic!(data_dir, {
+		let config = Config {
+			data_dir: data_dir.to_path_buf(),
+			max_active_size: 64,
+			max_block_size: 256,
+			active_shards: 2,
+			..Default::default()
+		};
+		let (storage, stop) = Storage::new(config)?;
+		tokio::task::yield_now().await;
+
+		let mut data = Vec::default();
+		for _ in 0..4 {
+			for doc in simple_data() {
+				storage.push(doc.key.clone(), doc.tags.clone()).await?;
+				data.push(doc);
+				tokio::task::yield_now().await;
+			}
+		}
+		wait_for(|| !storage.block_list().files.is_empty()).await;
+		for doc in data.iter_mut() {
+			doc.tags.sort();
+		}
+
+		let export = |query: &Query| -> Result<Vec<Record>, anyhow::Error> {
+			let mut records = Vec::default();
+			let count = storage.export(query, |record| {
+				records.push(record);
+				Ok(())
+			})?;
+			assert_eq!(count, records.len() as u64);
+			return Ok(records);
+		};
+
+		// every tier, oldest first
+		let all = export(&Query::new(vec![], (MIN_TIME, MAX_TIME)))?;
+		let docs: Vec<_> = all
+			.iter()
+			.map(|record| new_doc(&record.key, record.tags.clone()))
+			.collect();
+		assert_eq!(docs, data);
+		assert!(all.windows(2).all(|w| w[0].timestamp <= w[1].timestamp));
+
+		let tagged = export(&Query::new(vec_str!["tag0", "tag3"], (MIN_TIME, MAX_TIME)))?;
+		let expected: Vec<_> = data
+			.iter()
+			.filter(|doc| doc.tags.contains(&"tag0".to_string()))
+			.filter(|doc| doc.tags.contains(&"tag3".to_string()))
+			.cloned()
+			.collect();
+		let docs: Vec<_> = tagged
+			.iter()
+			.map(|record| new_doc(&record.key, record.tags.clone()))
+			.collect();
+		assert_eq!(docs, expected);
+
+		let last = all.last().unwrap().timestamp;
+		let range = export(&Query::new(vec![], (last, last)))?;
+		assert!(!range.is_empty());
+		assert!(range.iter().all(|record| record.timestamp == last));
+
+		// output errors stop the export
+		let mut calls = 0;
+		let result = storage.export(&Query::new(vec![], (MIN_TIME, MAX_TIME)), |_| {
+			calls += 1;
+			Err(anyhow::anyhow!("closed"))
+		});
+		assert!(result.is_err());
+		assert_eq!(calls, 1);
+
+		stop.await?;
+
+		Ok(())
+	})
+}
+
+#[test]
+fn snapshot() -> Result<(), anyhow::Error> {
+	tests::async_basic!(data_dir, {
+		let config = Config {
+			data_dir: data_dir.join("data"),
+			max_active_size: 512,
+			max_block_size: 2048,
+			..Default::default()
+		};
+		std::fs::create_dir_all(&config.data_dir)?;
+		let (storage, stop) = Storage::new(config.clone())?;
+		tokio::task::yield_now().await;
+
+		let mut data = Vec::default();
+		for _ in 0..3 {
+			for doc in simple_data() {
+				storage.push(doc.key.clone(), doc.tags.clone()).await?;
+				data.push(doc);
+			}
+		}
+		// nothing is on disk yet
+		assert!(storage.active_size.load(Ordering::SeqCst) > 0);
+		assert!(storage.block_list().files.is_empty());
+		for doc in data.iter_mut() {
+			doc.tags.sort();
+		}
+
+		let dest = data_dir.join("snapshot");
+		let files = storage.snapshot(&dest).await?;
+		assert!(files > 0);
+		// the source keeps working
+		storage.push("after".to_string(), vec_str!["tag0"]).await?;
+		assert!(storage.snapshot(&dest).await.is_err());
+		stop.await?;
+
+		let (copy, stop) = Storage::new(Config {
+			data_dir: dest,
+			..config
+		})?;
+		assert_eq!(copy.block_list().files.len(), files);
+		check_storage(&copy, &data);
+		stop.await?;
+
+		Ok(())
+	})
+}
+
+#[test]
+fn payloads() -> Result<(), anyhow::Error> {
+	tests::async_basic!(data_dir, {
+		let config = Config {
+			data_dir: data_dir.to_path_buf(),
+			max_active_size: 64,
+			max_block_size: 256,
+			..Default::default()
+		};
+		let (storage, stop) = Storage::new(config.clone())?;
+		tokio::task::yield_now().await;
+
+		let mut data = Vec::default();
+		for i in 0..4 {
+			for (j, mut doc) in simple_data().into_iter().enumerate() {
+				if j % 2 == 0 {
+					doc.payload = Some(serde_json::json!({ "i": i, "key": doc.key }));
+				}
+				storage.push_batch(vec![doc.clone()]).await?;
+				doc.tags.sort();
+				data.push(doc);
+				tokio::task::yield_now().await;
+			}
+		}
+		wait_for(|| !storage.block_list().files.is_empty()).await;
+		// every tier returns the payloads
+		check_storage(&storage, &data);
+
+		let query = Query::new(vec_str!["tag0"], (MIN_TIME, MAX_TIME));
+		let matches = storage.query(&query, 1000)?;
+		assert!(matches.iter().all(|m| m.payload.is_none()));
+		let matches = storage.query(&query.with_payload(true), 1000)?;
+		let expected: Vec<_> = data
+			.iter()
+			.rev()
+			.filter(|doc| doc.tags.contains(&"tag0".to_string()))
+			.map(|doc| doc.payload.clone())
+			.collect();
+		let payloads: Vec<_> = matches.into_iter().map(|m| m.payload).collect();
+		assert_eq!(payloads, expected);
+		stop.await?;
+
+		let (storage, stop) = Storage::new(config)?;
+		let (read, _) = read_all(storage.iter());
+		assert!(read.iter().any(|doc| doc.payload.is_some()));
+		assert_eq!(read[..], data[..read.len()]);
+		stop.await?;
+
+		Ok(())
+	})
+}
+
+async fn bench_shards(
+	data_dir: &std::path::Path,
+	active_shards: usize,
+	batches: Vec<Vec<Document>>,
+) -> Result<usize, anyhow::Error> {
+	let data_dir = data_dir.join(format!("shards{}", active_shards));
+	std::fs::create_dir_all(&data_dir)?;
+	let config = Config {
+		data_dir,
+		max_active_size: 32 * 1024,
+		max_block_size: 100 * 32 * 1024,
+		active_shards,
+		..Default::default()
+	};
+	let (storage, stop) = Storage::new(config)?;
+	let docs: usize = batches.iter().map(|batch| batch.len()).sum();
+
+	tokio::task::yield_now().await;
+
+	log::debug!("started: shards={}", active_shards);
+	let start = Instant::now();
+	let mut join = Vec::with_capacity(batches.len());
+	for batch in batches {
+		let storage = Arc::clone(&storage);
+		join.push(tokio::task::spawn(async move {
+			storage.push_batch(batch).await
+		}));
+	}
+
+	let err: Result<Vec<_>, _> = futures::future::join_all(join).await.into_iter().collect();
+	err?;
+
+	let took = Instant::now() - start;
+	let ops = docs * 1000000000 / took.as_nanos() as usize;
+	log::debug!("shards={} took {:.2?}; {}op/s", active_shards, took, ops);
+
+	stop.await?;
+
+	Ok(ops)
+}
+
+// measures the shards, it doesn't check anything, so it's run only by hand on a multi-core
+// machine: cargo test --release bench -- --ignored
+#[test]
+#[ignore]
+fn bench() -> Result<(), anyhow::Error> {
+	tests::async_threaded_basic!(data_dir, {
+		const BATCH_SIZE: usize = 1000;
+		const BATCHES_SIZE: usize = 100;
+		let batches = gen_vec(BATCHES_SIZE, |_| random_data(BATCH_SIZE));
+
+		let shards = 4;
+		let single = bench_shards(data_dir, 1, batches.clone()).await?;
+		let sharded = bench_shards(data_dir, shards, batches).await?;
+		let ratio = sharded as f64 / single as f64;
+		log::info!("{} shards: {:.2}x of a single shard", shards, ratio);
+
+		Ok(())
+	})
+}
@@ -0,0 +1,72 @@
+use super::*;
+use crate::tests;
+
+#[test]
+fn parse() -> Result<(), anyhow::Error> {
+	tests::run_with_logger(|| {
+		assert_eq!(parse_bytes("512")?, 512);
+		assert_eq!(parse_bytes("512B")?, 512);
+		assert_eq!(parse_bytes("64KiB")?, 64 * 1024);
+		assert_eq!(parse_bytes("64k")?, 64 * 1024);
+		assert_eq!(parse_bytes(" 4 MiB ")?, 4 * 1024 * 1024);
+		assert_eq!(parse_bytes("1gb")?, 1 << 30);
+		assert_eq!(parse_bytes("64kb")?, parse_bytes("64k")?);
+		assert_eq!(parse_bytes("4MB")?, parse_bytes("4MiB")?);
+		assert_eq!(parse_bytes("1t")?, 1 << 40);
+		assert_eq!(parse_bytes("2TiB")?, 2 << 40);
+
+		assert!(parse_bytes("").is_err());
+		assert!(parse_bytes("MiB").is_err());
+		assert!(parse_bytes("1.5MiB").is_err());
+		assert!(parse_bytes("10 parsecs").is_err());
+		assert!(parse_bytes("100000000TiB").is_err());
+
+		Ok(())
+	})
+}
+
+#[test]
+fn format() -> Result<(), anyhow::Error> {
+	tests::run_with_logger(|| {
+		assert_eq!(format_bytes(0), "0B");
+		assert_eq!(format_bytes(1000), "1000B");
+		assert_eq!(format_bytes(1024), "1KiB");
+		assert_eq!(format_bytes(1536), "1536B");
+		assert_eq!(format_bytes(4 << 20), "4MiB");
+		assert_eq!(format_bytes(3 << 40), "3TiB");
+
+		for size in [0, 1, 1000, 1024, 1536, 4 << 20, u64::MAX] {
+			assert_eq!(parse_bytes(&format_bytes(size))?, size);
+		}
+
+		Ok(())
+	})
+}
+
+#[test]
+fn durations() -> Result<(), anyhow::Error> {
+	tests::run_with_logger(|| {
+		assert_eq!(parse_duration("30")?, Duration::from_secs(30));
+		assert_eq!(parse_duration("500ms")?, Duration::from_millis(500));
+		assert_eq!(parse_duration("30s")?, Duration::from_secs(30));
+		assert_eq!(parse_duration(" 5 min")?, Duration::from_secs(5 * 60));
+		assert_eq!(parse_duration("2H")?, Duration::from_secs(2 * 3600));
+		assert_eq!(parse_duration("1d")?, Duration::from_secs(86400));
+
+		assert!(parse_duration("").is_err());
+		assert!(parse_duration("1.5s").is_err());
+		assert!(parse_duration("1 fortnight").is_err());
+
+		assert_eq!(format_duration(Duration::from_millis(0)), "0ms");
+		assert_eq!(format_duration(Duration::from_millis(1500)), "1500ms");
+		assert_eq!(format_duration(Duration::from_secs(90)), "90s");
+		assert_eq!(format_duration(Duration::from_secs(600)), "10m");
+		assert_eq!(format_duration(Duration::from_secs(7200)), "2h");
+		for millis in [0, 1, 1500, 60000, 86400000 * 3] {
+			let duration = Duration::from_millis(millis);
+			assert_eq!(parse_duration(&format_duration(duration))?, duration);
+		}
+
+		Ok(())
+	})
+}
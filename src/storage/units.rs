@@ -0,0 +1,138 @@
+use serde::{Deserialize, Deserializer, Serializer};
+use std::time::Duration;
+
+// all units are binary, so `64k`, `64kb` and `64KiB` are the same size
+const UNITS: [(&str, u64); 13] = [
+	("b", 1),
+	("k", 1 << 10),
+	("kb", 1 << 10),
+	("kib", 1 << 10),
+	("m", 1 << 20),
+	("mb", 1 << 20),
+	("mib", 1 << 20),
+	("g", 1 << 30),
+	("gb", 1 << 30),
+	("gib", 1 << 30),
+	("t", 1 << 40),
+	("tb", 1 << 40),
+	("tib", 1 << 40),
+];
+
+const DURATION_UNITS: [(&str, u64); 6] = [
+	("ms", 1),
+	("s", 1000),
+	("m", 60 * 1000),
+	("min", 60 * 1000),
+	("h", 60 * 60 * 1000),
+	("d", 24 * 60 * 60 * 1000),
+];
+
+// number with the unit, like `4 MiB`, without the unit the default multiplier is used
+fn parse_units(
+	s: &str,
+	units: &[(&str, u64)],
+	default: u64,
+	what: &str,
+) -> Result<u64, anyhow::Error> {
+	let s = s.trim();
+	let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+	let (number, unit) = s.split_at(split);
+	let number: u64 = number
+		.parse()
+		.map_err(|_| anyhow::anyhow!("invalid {}: {:?}", what, s))?;
+	let unit = unit.trim().to_ascii_lowercase();
+	let multiplier = if unit.is_empty() {
+		default
+	} else {
+		units
+			.iter()
+			.find(|(name, _)| *name == unit)
+			.map(|(_, multiplier)| *multiplier)
+			.ok_or_else(|| anyhow::anyhow!("unknown {} unit: {:?}", what, s))?
+	};
+	return number
+		.checked_mul(multiplier)
+		.ok_or_else(|| anyhow::anyhow!("{} is too big: {:?}", what, s));
+}
+
+/// Parses sizes like `512`, `64KiB`, `4 MiB` or `1gb`, all units are binary
+pub fn parse_bytes(s: &str) -> Result<u64, anyhow::Error> {
+	return parse_units(s, &UNITS, 1, "size");
+}
+
+/// Parses durations like `500ms`, `30s`, `5 min` or `1h`, plain numbers are seconds
+pub fn parse_duration(s: &str) -> Result<Duration, anyhow::Error> {
+	let millis = parse_units(s, &DURATION_UNITS, 1000, "duration")?;
+	return Ok(Duration::from_millis(millis));
+}
+
+/// The biggest binary unit, that represents the size exactly
+pub fn format_bytes(size: u64) -> String {
+	for (name, shift) in [("TiB", 40), ("GiB", 30), ("MiB", 20), ("KiB", 10)] {
+		if size != 0 && size.trailing_zeros() >= shift {
+			return format!("{}{}", size >> shift, name);
+		}
+	}
+	return format!("{}B", size);
+}
+
+/// The biggest unit, that represents the duration exactly, up to milliseconds
+pub fn format_duration(duration: Duration) -> String {
+	let millis = duration.as_millis() as u64;
+	for (name, multiplier) in [("d", 86400000), ("h", 3600000), ("m", 60000), ("s", 1000)] {
+		if millis != 0 && millis.is_multiple_of(multiplier) {
+			return format!("{}{}", millis / multiplier, name);
+		}
+	}
+	return format!("{}ms", millis);
+}
+
+/// For serde `with` attribute: sizes are written with units, and read either with them
+/// or as plain numbers of bytes
+pub mod bytes {
+	use super::*;
+
+	pub fn serialize<S: Serializer>(size: &u64, serializer: S) -> Result<S::Ok, S::Error> {
+		serializer.serialize_str(&format_bytes(*size))
+	}
+
+	pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+		#[derive(Deserialize)]
+		#[serde(untagged)]
+		enum Size {
+			Number(u64),
+			String(String),
+		}
+		match Size::deserialize(deserializer)? {
+			Size::Number(size) => Ok(size),
+			Size::String(s) => parse_bytes(&s).map_err(serde::de::Error::custom),
+		}
+	}
+}
+
+/// For serde `with` attribute: durations are written with units, and read either with them
+/// or as plain numbers of seconds
+pub mod duration {
+	use super::*;
+
+	pub fn serialize<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+		serializer.serialize_str(&format_duration(*duration))
+	}
+
+	pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+		#[derive(Deserialize)]
+		#[serde(untagged)]
+		enum Value {
+			Number(u64),
+			String(String),
+		}
+		match Value::deserialize(deserializer)? {
+			Value::Number(seconds) => Ok(Duration::from_secs(seconds)),
+			Value::String(s) => parse_duration(&s).map_err(serde::de::Error::custom),
+		}
+	}
+}
+
+#[cfg(test)]
+#[path = "tests/units.rs"]
+mod units_test;